@@ -0,0 +1,217 @@
+// Lanczos3 resampling in a compute shader, used by --gpu when built with the `gpu` feature
+use image::{DynamicImage, RgbaImage};
+use std::sync::OnceLock;
+use wgpu::util::DeviceExt;
+
+// Same kernel as image's FilterType::Lanczos3, widened when downscaling so it averages
+// every source pixel instead of skipping some
+const SHADER: &str = r#"
+struct Params {
+    src_width: u32,
+    src_height: u32,
+    dst_width: u32,
+    dst_height: u32,
+}
+
+@group(0) @binding(0) var<uniform> params: Params;
+@group(0) @binding(1) var<storage, read> src: array<u32>;
+@group(0) @binding(2) var<storage, read_write> dst: array<u32>;
+
+const PI: f32 = 3.14159265358979;
+
+fn sinc(x: f32) -> f32 {
+    if abs(x) < 1e-5 {
+        return 1.0;
+    }
+    let a = x * PI;
+    return sin(a) / a;
+}
+
+fn lanczos3(x: f32) -> f32 {
+    if abs(x) >= 3.0 {
+        return 0.0;
+    }
+    return sinc(x) * sinc(x / 3.0);
+}
+
+@compute @workgroup_size(8, 8)
+fn main(@builtin(global_invocation_id) id: vec3<u32>) {
+    if id.x >= params.dst_width || id.y >= params.dst_height {
+        return;
+    }
+
+    let src_size = vec2<f32>(f32(params.src_width), f32(params.src_height));
+    let ratio = src_size / vec2<f32>(f32(params.dst_width), f32(params.dst_height));
+    let scale = max(ratio, vec2<f32>(1.0, 1.0));
+    let support = 3.0 * scale;
+    let center = (vec2<f32>(id.xy) + 0.5) * ratio;
+
+    let lo = vec2<i32>(max(floor(center - support), vec2<f32>(0.0, 0.0)));
+    let hi = vec2<i32>(min(ceil(center + support), src_size));
+
+    var sum = vec4<f32>(0.0);
+    var weight_sum = 0.0;
+    for (var y = lo.y; y < hi.y; y++) {
+        let wy = lanczos3((f32(y) + 0.5 - center.y) / scale.y);
+        if wy == 0.0 {
+            continue;
+        }
+        for (var x = lo.x; x < hi.x; x++) {
+            let w = lanczos3((f32(x) + 0.5 - center.x) / scale.x) * wy;
+            sum += unpack4x8unorm(src[u32(y) * params.src_width + u32(x)]) * w;
+            weight_sum += w;
+        }
+    }
+
+    let color = clamp(sum / weight_sum, vec4<f32>(0.0), vec4<f32>(1.0));
+    dst[id.y * params.dst_width + id.x] = pack4x8unorm(color);
+}
+"#;
+
+pub struct Resizer {
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    pipeline: wgpu::ComputePipeline,
+    max_buffer_size: u64,
+}
+
+static RESIZER: OnceLock<Option<Resizer>> = OnceLock::new();
+
+// Set up the shared device once; false means no adapter was found and resizing stays on the CPU
+pub fn init() -> bool {
+    RESIZER.get_or_init(|| pollster::block_on(Resizer::new())).is_some()
+}
+
+// None when the GPU isn't initialized or can't take this image, so the caller resizes on the CPU
+pub fn resize(img: &DynamicImage, width: u32, height: u32) -> Option<DynamicImage> {
+    let resizer = RESIZER.get()?.as_ref()?;
+
+    // Pixels are packed into one u32 each, so wider channels would lose precision
+    let resized = match img {
+        DynamicImage::ImageRgba8(rgba) => DynamicImage::ImageRgba8(resizer.resize(rgba, width, height)?),
+        DynamicImage::ImageRgb8(_) => DynamicImage::ImageRgb8(
+            DynamicImage::ImageRgba8(resizer.resize(&img.to_rgba8(), width, height)?).to_rgb8(),
+        ),
+        DynamicImage::ImageLuma8(_) => DynamicImage::ImageLuma8(
+            DynamicImage::ImageRgba8(resizer.resize(&img.to_rgba8(), width, height)?).to_luma8(),
+        ),
+        DynamicImage::ImageLumaA8(_) => DynamicImage::ImageLumaA8(
+            DynamicImage::ImageRgba8(resizer.resize(&img.to_rgba8(), width, height)?).to_luma_alpha8(),
+        ),
+        _ => return None,
+    };
+    Some(resized)
+}
+
+impl Resizer {
+    pub async fn new() -> Option<Self> {
+        let instance = wgpu::Instance::new(&wgpu::InstanceDescriptor::default());
+        let adapter = instance
+            .request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::HighPerformance,
+                ..Default::default()
+            })
+            .await
+            .ok()?;
+
+        // Software rasterizers such as llvmpipe are slower than image's own resize
+        if adapter.get_info().device_type == wgpu::DeviceType::Cpu {
+            return None;
+        }
+
+        // Ask for the adapter's own limits so large photos fit in a single storage buffer
+        let limits = adapter.limits();
+        let (device, queue) = adapter
+            .request_device(&wgpu::DeviceDescriptor {
+                label: Some("image-resizer"),
+                required_limits: limits.clone(),
+                ..Default::default()
+            })
+            .await
+            .ok()?;
+
+        let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("lanczos3"),
+            source: wgpu::ShaderSource::Wgsl(SHADER.into()),
+        });
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("lanczos3"),
+            layout: None,
+            module: &module,
+            entry_point: Some("main"),
+            compilation_options: Default::default(),
+            cache: None,
+        });
+
+        Some(Resizer {
+            device,
+            queue,
+            pipeline,
+            max_buffer_size: u64::from(limits.max_storage_buffer_binding_size).min(limits.max_buffer_size),
+        })
+    }
+
+    pub fn resize(&self, img: &RgbaImage, width: u32, height: u32) -> Option<RgbaImage> {
+        let src_bytes = img.as_raw().len() as u64;
+        let dst_bytes = u64::from(width) * u64::from(height) * 4;
+        if width == 0 || height == 0 || src_bytes == 0 || src_bytes.max(dst_bytes) > self.max_buffer_size {
+            return None;
+        }
+
+        let params = [img.width(), img.height(), width, height];
+        let params_buffer = self.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("params"),
+            contents: &params.iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<u8>>(),
+            usage: wgpu::BufferUsages::UNIFORM,
+        });
+        let src_buffer = self.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("src"),
+            contents: img.as_raw(),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+        let dst_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("dst"),
+            size: dst_bytes,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let readback = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("readback"),
+            size: dst_bytes,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &self.pipeline.get_bind_group_layout(0),
+            entries: &[
+                wgpu::BindGroupEntry { binding: 0, resource: params_buffer.as_entire_binding() },
+                wgpu::BindGroupEntry { binding: 1, resource: src_buffer.as_entire_binding() },
+                wgpu::BindGroupEntry { binding: 2, resource: dst_buffer.as_entire_binding() },
+            ],
+        });
+
+        let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor::default());
+        {
+            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor::default());
+            pass.set_pipeline(&self.pipeline);
+            pass.set_bind_group(0, &bind_group, &[]);
+            pass.dispatch_workgroups(width.div_ceil(8), height.div_ceil(8), 1);
+        }
+        encoder.copy_buffer_to_buffer(&dst_buffer, 0, &readback, 0, dst_bytes);
+        self.queue.submit([encoder.finish()]);
+
+        let slice = readback.slice(..);
+        let (tx, rx) = std::sync::mpsc::channel();
+        slice.map_async(wgpu::MapMode::Read, move |result| {
+            let _ = tx.send(result);
+        });
+        self.device.poll(wgpu::PollType::Wait).ok()?;
+        rx.recv().ok()?.ok()?;
+
+        let pixels = slice.get_mapped_range().to_vec();
+        readback.unmap();
+        RgbaImage::from_raw(width, height, pixels)
+    }
+}
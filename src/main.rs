@@ -1,26 +1,359 @@
 use clap::{Arg, Command};
-use image::{DynamicImage, ImageFormat, ImageError};
+use image::{AnimationDecoder, DynamicImage, GenericImageView, ImageFormat};
 use indicatif::{ProgressBar, ProgressStyle};
 use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs;
-use std::io::Cursor;
+use std::io::{Cursor, Read};
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 use walkdir::WalkDir;
 
-#[derive(Debug, Clone)]
+#[cfg(feature = "gpu")]
+mod gpu;
+
+// Added to output stems so they never overwrite their input
+const RESIZED_SUFFIX: &str = "_resized";
+
+// Largest palette --palette-from accepts, matching indexed formats like GIF and PNG
+const MAX_PALETTE_COLORS: usize = 256;
+
+// Appended to an input's file name to find its per-image overrides
+const SIDECAR_SUFFIX: &str = ".resize.toml";
+
+// Bumped whenever a --report field is renamed, removed or changes meaning; new fields don't bump it
+const REPORT_SCHEMA_VERSION: u32 = 1;
+
+// Most files --summary-threshold lists, worst first
+const WORST_FILES_LISTED: usize = 10;
+
+// Number of images encoded in memory by --plan
+const PLAN_SAMPLE_SIZE: usize = 10;
+
+// Upper bounds of the --collect-stats histogram bins; the last bin is open-ended
+const STATS_EDGE_BINS: &[u32] = &[640, 1280, 1920, 2560, 3840];
+const STATS_SIZE_BINS_KB: &[u64] = &[100, 500, 1024, 2048, 5120];
+const STATS_BAR_WIDTH: usize = 40;
+
+// Qualities on either side of the search result that --two-pass re-encodes
+const TWO_PASS_RADIUS: u8 = 2;
+
+// --sample-pixels skips images whose shorter side is below this, where encodes are already cheap,
+// and first steps this far from the calibrated estimate when bracketing it at full size
+const SAMPLE_MIN_SIDE: u32 = 256;
+const SAMPLE_STEP: u8 = 1;
+
+// Cells per side that --sample-pixels takes one tile from
+const SAMPLE_GRID: u32 = 4;
+
+// Extensions collected when --input-formats is not given
+#[cfg(not(feature = "qoi"))]
+const DEFAULT_INPUT_FORMATS: &[&str] = &["jpg", "jpeg", "png", "gif", "bmp", "webp", "tiff", "tif", "ico"];
+#[cfg(feature = "qoi")]
+const DEFAULT_INPUT_FORMATS: &[&str] = &["jpg", "jpeg", "png", "gif", "bmp", "webp", "tiff", "tif", "ico", "qoi"];
+
+// How long a new file must stop growing before --watch processes it
+const WATCH_SETTLE_TIME: Duration = Duration::from_millis(500);
+
+// Square cell each thumbnail is fitted into on a --contact-sheet
+const CONTACT_SHEET_CELL: u32 = 160;
+
+// Space around each cell and below it for the 8x8 pixel file name caption
+const CONTACT_SHEET_PADDING: u32 = 8;
+const CONTACT_SHEET_CAPTION: u32 = 16;
+
+// Side of the square windows --metrics averages SSIM over, and the PSNR reported for identical pixels
+const SSIM_WINDOW: u32 = 8;
+const MAX_PSNR: f64 = 100.0;
+
+// Headerless RGBA inputs read by --raw-input, recognized by extension since they have no signature
+const RAW_EXTENSIONS: &[&str] = &["raw", "data"];
+
+// --stream-decode keeps at least this many times the target resolution for the final Lanczos3 pass
+const STREAM_OVERSAMPLE: f64 = 2.0;
+
+// --fast-thumbnails only swaps the filter when neither output side is larger than this
+const FAST_THUMBNAIL_MAX_SIDE: u32 = 256;
+
+// --lanczos-window beyond this many lobes only adds ringing and time
+const MAX_LANCZOS_WINDOW: u32 = 8;
+
+// Adobe APP14 transform value for JPEGs stored as YCbCr plus K
+const ADOBE_YCCK: u8 = 2;
+
+// --quality-curve without a value: full color down to 90, then ever smaller palettes
+const DEFAULT_QUALITY_CURVE: &str = "90:best:adaptive:0,75:best:adaptive:256,50:best:adaptive:128,30:best:adaptive:64,1:best:adaptive:32";
+
+// --equal-output-size warns about images where more than this share of the output is padding,
+// or of the scaled image is cropped away
+const SIGNIFICANT_MISMATCH: f64 = 0.25;
+
+// Quality for JPEG and WebP when there is no size target to search for
+const DEFAULT_QUALITY: u8 = 90;
+
+// A JPEG COM segment length is 16 bits and includes the two length bytes
+const MAX_JPEG_COMMENT_LEN: usize = 65533;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "kebab-case")]
 struct Config {
     input_path: PathBuf,
+    // Set when --input is a pattern such as photos/**/*.jpg; input_path is then its fixed leading folder
+    input_glob: Option<String>,
+    stdin_list: bool,
     target_size_kb: Option<u64>,
+    size_tolerance: Option<f64>,
     dimensions: Option<(u32, u32)>,
+    megapixels: Option<f64>,
+    edge: Option<Edge>,
+    allow_upscale: bool,
+    round_dimensions: Option<u32>,
+    trim: Option<u8>,
+    normalize_orientation: Option<Orientation>,
+    fail_on_upscale: bool,
+    downscale_only: bool,
+    resampling: Resampling,
+    letterbox: bool,
+    equal_output_size: Option<(u32, u32)>,
+    equal_output_fit: EqualFit,
+    #[serde(serialize_with = "serialize_color")]
+    background: image::Rgba<u8>,
+    #[serde(serialize_with = "serialize_palette")]
+    palette: Option<Palette>,
+    dither: bool,
     output_dir: Option<PathBuf>,
+    // Set when -o names a single file such as out.jpg; output_dir is then its folder
+    output_file: Option<PathBuf>,
+    overwrite_input: bool,
+    output_temp_dir: Option<PathBuf>,
+    preserve_permissions: bool,
+    preserve_mtime: bool,
+    copy_timestamps: bool,
     maintain_aspect_ratio: bool,
     parallel: bool,
+    gpu: bool,
     verbose: bool,
-	auto_scale: bool
+	auto_scale: bool,
+    // Smallest scale in percent that auto-scaling may reach
+    auto_scale_min: u8,
+    auto_scale_start: f32,
+    auto_scale_step: f32,
+    dpi: Option<u16>,
+    plan: bool,
+    collect_stats: bool,
+    no_write: bool,
+    to_srgb: bool,
+    stream_decode: bool,
+    keep_smaller: bool,
+    recompress_above_kb: Option<u64>,
+    validate_output: bool,
+    // Program and arguments of --post-command, already split like a shell would
+    post_command: Option<Vec<String>>,
+    metrics: bool,
+    ico_sizes: Option<Vec<u32>>,
+    raw_input: Option<(u32, u32)>,
+    raw_output: bool,
+    profile: bool,
+    group_by_directory: bool,
+    summary_threshold: Option<f64>,
+    #[serde(serialize_with = "serialize_template")]
+    name_template: Option<Vec<TemplatePart>>,
+    no_suffix_when_converting: bool,
+    no_suffix_in_output_dir: bool,
+    strip_resized_suffix: bool,
+    date_prefix: bool,
+    date_fallback: DateFallback,
+    check_exif_dimensions: bool,
+    two_pass: bool,
+    sample_pixels: bool,
+    subsampling: Option<Subsampling>,
+    optimize: bool,
+    webp_effort: u8,
+    encode_threads: u32,
+    webp_lossy: bool,
+    near_lossless: Option<u8>,
+    // Set per image for lossless WebP sources, never from the command line
+    #[serde(skip)]
+    webp_lossless: bool,
+    png_bit_depth: Option<u8>,
+    quality_curve: Option<Vec<CurvePoint>>,
+    force_color: Option<ForceColor>,
+    transparent_to_white: bool,
+    watch: bool,
+    exclude_dirs: Vec<String>,
+    copy_unsupported: bool,
+    resume: Option<PathBuf>,
+    report: Option<PathBuf>,
+    failures_list: Option<PathBuf>,
+    error_log: Option<PathBuf>,
+    pretty: bool,
+    contact_sheet: Option<PathBuf>,
+    columns: u32,
+    hash_algorithm: Option<HashAlgorithm>,
+    input_formats: Option<Vec<String>>,
+    shuffle: bool,
+    seed: Option<u64>,
+    limit: Option<usize>,
+    max_files: Option<usize>,
+    // --yes: go past --max-files with a warning instead of aborting
+    over_max_files: bool,
+    comment: Option<String>,
+    flatten_gif: bool,
+    animated_webp: bool,
+    frame: Option<usize>,
+    skip_animated: bool,
+    min_dimensions: Option<(u32, u32)>,
+    min_quality: u8,
+    max_quality: u8,
+    warn_below_quality: u8,
+    format_quality: FormatQuality,
+    prefer: Prefer,
+    #[serde(serialize_with = "serialize_format")]
+    fallback_format: Option<ImageFormat>,
+    summary_json: bool,
+    progress_json: bool,
+    quiet: bool,
+}
+
+// Target length for one side picked per image by --long-edge / --short-edge
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum Edge {
+    Long(u32),
+    Short(u32),
+}
+
+// How --equal-output-size reaches the exact size when the aspect ratio differs
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum EqualFit {
+    Pad,
+    Crop,
+}
+
+// How every resize picks its filter: --fast-thumbnails and --lanczos-window
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Resampling {
+    fast_thumbnails: bool,
+    lanczos_window: u32,
 }
 
-#[derive(Debug)]
+// Fixed colors loaded by --palette-from
+#[derive(Debug, Clone)]
+struct Palette {
+    colors: Vec<[u8; 3]>,
+}
+
+// Output channel layout pinned by --force-rgb / --force-rgba
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum ForceColor {
+    Rgb,
+    Rgba,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+enum Subsampling {
+    #[serde(rename = "444")]
+    S444,
+    #[serde(rename = "422")]
+    S422,
+    #[serde(rename = "420")]
+    S420,
+}
+
+// Fixed quality per output format from --format-quality, used when there is no size target
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+struct FormatQuality {
+    jpeg: Option<u8>,
+    webp: Option<u8>,
+    // Only has an effect with --quality-curve
+    png: Option<u8>,
+}
+
+// One step of --quality-curve; applies from `quality` up to the next step
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct CurvePoint {
+    quality: u8,
+    compression: PngCompression,
+    filter: PngFilter,
+    // 0 keeps every color, anything else quantizes to an indexed PNG
+    colors: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum PngCompression {
+    Fast,
+    Default,
+    Best,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum PngFilter {
+    None,
+    Sub,
+    Up,
+    Avg,
+    Paeth,
+    Adaptive,
+}
+
+// Which lever smart_compress pulls first to reach a size target
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Prefer {
+    Quality,
+    Scale,
+    Balanced,
+}
+
+// Shape --normalize-orientation turns every image into
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Orientation {
+    Portrait,
+    Landscape,
+}
+
+// What --date-prefix uses when a file has no EXIF capture date
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum DateFallback {
+    Mtime,
+    #[serde(rename = "none")]
+    Skip,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum HashAlgorithm {
+    Blake3,
+    Sha256,
+    XxHash,
+}
+
+#[derive(Debug, Clone)]
+enum TemplatePart {
+    Literal(String),
+    Stem,
+    Width,
+    Height,
+    Quality,
+    Scale,
+    Format,
+    Index,
+    Ext,
+}
+
+#[derive(Debug, Default)]
 struct ProcessResult {
     input_path: PathBuf,
     output_path: PathBuf,
@@ -28,10 +361,186 @@ struct ProcessResult {
     final_size: u64,
     success: bool,
     message: String,
+    // Sources under the failure's error and its Debug form, for --error-log
+    error_detail: Vec<String>,
+    format: Option<ImageFormat>,
+    kept_original: bool,
+    // Copied unchanged by --recompress-only-if-larger-than
+    passed_through: bool,
+    // Failed because the contents couldn't be decoded, so --copy-unsupported copies it instead
+    undecodable: bool,
+    // Turned a quarter by --normalize-orientation
+    rotated: bool,
+    // Lossy output encoded below --warn-below-quality
+    below_quality: bool,
+    // Saved in the --force-format-on-failure format instead of this one
+    converted_from: Option<ImageFormat>,
+    // Pixel size of the written file, after any scaling by the size search
+    dimensions: Option<(u32, u32)>,
+    // Only kept for --contact-sheet
+    thumbnail: Option<image::RgbaImage>,
+    metrics: Option<QualityMetrics>,
+    // Exit code of --post-command; None when it didn't run or was killed by a signal
+    post_exit_code: Option<i32>,
+    timings: StageTimings,
+    warnings: Vec<String>,
+    // Verbose lines buffered by a --parallel worker, printed with this file's detailed result
+    log: Vec<String>,
+}
+
+// Computed by --metrics between the original and the written output
+#[derive(Debug, Clone, Copy, Serialize)]
+struct QualityMetrics {
+    ssim: f64,
+    psnr: f64,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct StageTimings {
+    decode: Duration,
+    color: Duration,
+    resize: Duration,
+    compress: Duration,
+    encode: Duration,
+    write: Duration,
+}
+
+// Batch totals printed by --summary-json
+#[derive(Debug, Default, Serialize)]
+struct BatchSummary {
+    total: usize,
+    successful: usize,
+    failed: usize,
+    kept_original: usize,
+    original_bytes: u64,
+    final_bytes: u64,
+    saved_bytes: u64,
+    saved_percent: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    average_ssim: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    average_psnr: Option<f64>,
+}
+
+// Written by --report
+#[derive(Debug, Serialize)]
+struct Report<'a> {
+    schema_version: u32,
+    summary: &'a BatchSummary,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hash_algorithm: Option<&'static str>,
+    files: Vec<FileReport>,
+}
+
+#[derive(Debug, Serialize)]
+struct FileReport {
+    input: PathBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output: Option<PathBuf>,
+    success: bool,
+    original_bytes: u64,
+    final_bytes: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    height: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ssim: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    psnr: Option<f64>,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hash: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    post_exit_code: Option<i32>,
+}
+
+// Settings read from a `<name>.resize.toml` next to an input; unset fields keep the global value
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct Sidecar {
+    size: Option<u64>,
+    dimensions: Option<String>,
+    maintain_ratio: Option<bool>,
+    auto_scale: Option<bool>,
+    min_quality: Option<u8>,
+    max_quality: Option<u8>,
+    quality_curve: Option<String>,
+}
+
+// Streamed to stderr by --progress-json, one JSON object per line
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+enum ProgressEvent<'a> {
+    Start {
+        total: usize,
+    },
+    File {
+        completed: usize,
+        total: usize,
+        input: &'a Path,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        output: Option<&'a Path>,
+        success: bool,
+        original_bytes: u64,
+        final_bytes: u64,
+        #[serde(skip_serializing_if = "str::is_empty")]
+        message: &'a str,
+    },
+    Done(&'a BatchSummary),
+}
+
+// One line of the --resume state file
+#[derive(Debug, Serialize, Deserialize)]
+struct ResumeEntry {
+    input: PathBuf,
+}
+
+struct ProcessOutcome {
+    output_path: PathBuf,
+    kept_original: bool,
+    passed_through: bool,
+    rotated: bool,
+    dimensions: (u32, u32),
+    thumbnail: Option<image::RgbaImage>,
+    metrics: Option<QualityMetrics>,
+    low_quality: Option<u8>,
+    converted_from: Option<ImageFormat>,
+    mismatch: Option<f64>,
+    // Encoded size for --no-write, which never creates the output file
+    discarded_size: Option<u64>,
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let matches = Command::new("Image Resizer Pro")
+    let matches = cli().get_matches();
+    let config = config_from_matches(&matches)?;
+
+    // Printed after validation so only settings that would actually run are shown
+    if matches.get_flag("print-config") {
+        println!("{}", serde_json::to_string_pretty(&config)?);
+        return Ok(());
+    }
+
+    if config.gpu {
+        #[cfg(feature = "gpu")]
+        if !gpu::init() && !config.quiet {
+            println!("⚠️  No GPU adapter found, resizing on the CPU");
+        }
+        #[cfg(not(feature = "gpu"))]
+        return Err("--gpu requires a build with the gpu feature (cargo build --features gpu)".into());
+    }
+
+    process_images(&config)?;
+
+    if config.watch {
+        watch_images(&config)?;
+    }
+    Ok(())
+}
+
+fn cli() -> Command {
+    Command::new("Image Resizer Pro")
         .version("1.1")
         .author("Your Name")
         .about("Advanced image resizing by file size and/or dimensions")
@@ -40,8 +549,16 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .short('i')
                 .long("input")
                 .value_name("PATH")
-                .help("Input image file or directory")
-                .required(true),
+                .help("Input image file or directory, or a glob such as 'photos/**/*.jpg'")
+                .required_unless_present("stdin-list"),
+        )
+        .arg(
+            Arg::new("stdin-list")
+                .long("stdin-list")
+                .alias("input-list-recursive-from-stdin")
+                .help("Read newline-separated image paths from stdin instead of walking --input")
+                .conflicts_with_all(["input", "watch", "copy-unsupported"])
+                .action(clap::ArgAction::SetTrue),
         )
         .arg(
             Arg::new("size")
@@ -49,21 +566,229 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .long("size")
                 .value_name("KB")
                 .help("Target file size in KB")
+                .env("IMAGE_RESIZER_SIZE_KB")
                 .value_parser(clap::value_parser!(u64)),
         )
+        .arg(
+            Arg::new("size-tolerance")
+                .long("size-tolerance")
+                .value_name("PERCENT")
+                .help("Accept the first result within this percentage of the target size, above or below")
+                .value_parser(clap::value_parser!(f64))
+                .requires("size"),
+        )
         .arg(
             Arg::new("dimensions")
                 .short('d')
                 .long("dimensions")
                 .value_name("WIDTHxHEIGHT")
-                .help("Target dimensions (e.g., 800x600)"),
+                .help("Target dimensions (e.g., 800x600, or 4inx6in and 10cmx15cm with --dpi)")
+                .env("IMAGE_RESIZER_DIMENSIONS"),
+        )
+        .arg(
+            Arg::new("megapixels")
+                .long("megapixels")
+                .value_name("MP")
+                .help("Downscale so the image has at most this many megapixels, keeping aspect ratio")
+                .value_parser(clap::value_parser!(f64))
+                .conflicts_with("dimensions"),
+        )
+        .arg(
+            Arg::new("long-edge")
+                .long("long-edge")
+                .value_name("PIXELS")
+                .help("Downscale so the longer side is at most this long, whatever the orientation")
+                .value_parser(clap::value_parser!(u32).range(1..))
+                .conflicts_with_all(["dimensions", "megapixels", "short-edge"]),
+        )
+        .arg(
+            Arg::new("short-edge")
+                .long("short-edge")
+                .value_name("PIXELS")
+                .help("Downscale so the shorter side is at most this long, whatever the orientation")
+                .value_parser(clap::value_parser!(u32).range(1..))
+                .conflicts_with_all(["dimensions", "megapixels"]),
+        )
+        .arg(
+            Arg::new("allow-upscale")
+                .long("allow-upscale")
+                .help("Let --long-edge and --short-edge enlarge images smaller than the target")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("trim")
+                .long("trim")
+                .help("Crop away uniform-colored borders before resizing")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("normalize-orientation")
+                .long("normalize-orientation")
+                .value_name("ORIENTATION")
+                .help("Rotate images 90° clockwise when they aren't already this shape, for uniform galleries")
+                .value_parser(["portrait", "landscape"]),
+        )
+        .arg(
+            Arg::new("trim-tolerance")
+                .long("trim-tolerance")
+                .value_name("0-255")
+                .help("Largest per-channel difference from the corner color still trimmed as border (default: 10)")
+                .value_parser(clap::value_parser!(u8))
+                .default_value("10")
+                .requires("trim"),
+        )
+        .arg(
+            Arg::new("fail-on-upscale")
+                .long("fail-on-upscale")
+                .help("Fail images that --dimensions would enlarge instead of upscaling them")
+                .requires("dimensions")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("downscale-only")
+                .long("downscale-only")
+                .help("Never enlarge an image; smaller images keep their size under every resize option")
+                .action(clap::ArgAction::SetTrue)
+                .conflicts_with_all(["fail-on-upscale", "allow-upscale"]),
+        )
+        .arg(
+            Arg::new("fast-thumbnails")
+                .long("fast-thumbnails")
+                .help("Resample outputs of at most 256px per side with the faster Triangle filter instead of Lanczos3")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("lanczos-window")
+                .long("lanczos-window")
+                .alias("filter-param")
+                .value_name("LOBES")
+                .help("Lanczos window in lobes: 2 is softer with less ringing, higher is sharper")
+                .value_parser(clap::value_parser!(u32).range(1..=MAX_LANCZOS_WINDOW as i64))
+                .default_value("3"),
+        )
+        .arg(
+            Arg::new("letterbox")
+                .long("letterbox")
+                .help("Fit inside --dimensions and pad with --background to the exact size")
+                .requires("dimensions")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("background")
+                .long("background")
+                .value_name("COLOR")
+                .help("Background color as RRGGBB or RRGGBBAA hex (default: ffffff)")
+                .value_parser(parse_color)
+                .default_value("ffffff"),
+        )
+        .arg(
+            Arg::new("equal-output-size")
+                .long("equal-output-size")
+                .value_name("WIDTHxHEIGHT")
+                .help("Make every output exactly this size by fitting and padding (or cropping), e.g. for ML datasets")
+                .value_parser(|s: &str| {
+                    parse_dimensions(Some(&s.to_string()), None)
+                        .ok()
+                        .flatten()
+                        .filter(|&(width, height)| width > 0 && height > 0)
+                        .ok_or_else(|| format!("invalid size '{}', expected WIDTHxHEIGHT", s))
+                })
+                .conflicts_with_all([
+                    "dimensions",
+                    "megapixels",
+                    "long-edge",
+                    "short-edge",
+                    "round-dimensions",
+                    "downscale-only",
+                    "auto-scale",
+                    "keep-smaller",
+                    "recompress-only-if-larger-than",
+                    "ico-sizes",
+                ]),
+        )
+        .arg(
+            Arg::new("equal-output-fit")
+                .long("equal-output-fit")
+                .value_name("MODE")
+                .help("Pad with --background around the fitted image (pad) or fill the box and crop the overflow (crop)")
+                .value_parser(["pad", "crop"])
+                .default_value("pad")
+                .requires("equal-output-size"),
+        )
+        .arg(
+            Arg::new("palette-from")
+                .long("palette-from")
+                .value_name("FILE")
+                .help("Remap colors to the nearest entry of a palette from an image, .gpl or .act file"),
+        )
+        .arg(
+            Arg::new("dither")
+                .long("dither")
+                .help("Use Floyd-Steinberg dithering when remapping to --palette-from")
+                .requires("palette-from")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("round-dimensions")
+                .long("round-dimensions")
+                .value_name("N")
+                .help("Round resized width and height down to a multiple of N (e.g., 2, 8, 16)")
+                .value_parser(clap::value_parser!(u32).range(1..)),
         )
         .arg(
             Arg::new("output")
                 .short('o')
                 .long("output")
+                .value_name("PATH")
+                .help("Output directory (default: creates 'resized' subdirectory), or an output file like out.jpg for a single input")
+                .env("IMAGE_RESIZER_OUTPUT"),
+        )
+        .arg(
+            Arg::new("overwrite-input")
+                .long("overwrite-input")
+                .help("DESTRUCTIVE: replace each original file with its resized version (requires --yes)")
+                .action(clap::ArgAction::SetTrue)
+                .conflicts_with_all([
+                    "output",
+                    "name-template",
+                    "output-suffix-none-when-converting",
+                    "strip-resized-suffix-on-input",
+                    "date-prefix",
+                    "copy-unsupported",
+                    "watch",
+                ]),
+        )
+        .arg(
+            Arg::new("output-temp-dir")
+                .long("output-temp-dir")
                 .value_name("DIR")
-                .help("Output directory (default: creates 'resized' subdirectory)"),
+                .help("Write --overwrite-input temporary files here instead of next to each original")
+                .requires("overwrite-input")
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("preserve-permissions")
+                .long("preserve-permissions")
+                .help("Give each output the permission bits of its source file")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("preserve-mtime")
+                .long("preserve-mtime")
+                .help("Give each output the modification time of its source file")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("copy-timestamps")
+                .long("copy-timestamps")
+                .help("Give each output the modification and access times of its source file")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("yes")
+                .long("yes")
+                .help("Confirm --overwrite-input and runs over the --max-files cap")
+                .action(clap::ArgAction::SetTrue),
         )
 		.arg(
             Arg::new("auto-scale")
@@ -72,6 +797,30 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .help("Auto scale image to fit target size, default: false")
                 .action(clap::ArgAction::SetTrue),
         )
+        .arg(
+            Arg::new("auto-scale-min")
+                .long("auto-scale-min")
+                .value_name("PERCENT")
+                .help("Smallest scale auto-scaling may go down to (default: 30)")
+                .value_parser(clap::value_parser!(u8).range(1..=100))
+                .default_value("30"),
+        )
+        .arg(
+            Arg::new("auto-scale-start")
+                .long("auto-scale-start")
+                .value_name("FACTOR")
+                .help("First scale auto-scaling tries, between 0 and 1 (default: 0.95)")
+                .value_parser(clap::value_parser!(f32))
+                .default_value("0.95"),
+        )
+        .arg(
+            Arg::new("auto-scale-step")
+                .long("auto-scale-step")
+                .value_name("FACTOR")
+                .help("Multiply the scale by this after each miss, between 0 and 1; closer to 1 is finer but slower (default: 0.85)")
+                .value_parser(clap::value_parser!(f32))
+                .default_value("0.85"),
+        )
         .arg(
             Arg::new("maintain-ratio")
                 .short('r')
@@ -86,6 +835,12 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .help("Process images in parallel")
                 .action(clap::ArgAction::SetTrue),
         )
+        .arg(
+            Arg::new("gpu")
+                .long("gpu")
+                .help("Resample on the GPU when one is available (requires the gpu feature)")
+                .action(clap::ArgAction::SetTrue),
+        )
         .arg(
             Arg::new("verbose")
                 .short('v')
@@ -93,414 +848,5101 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .help("Show detailed processing information")
                 .action(clap::ArgAction::SetTrue),
         )
-        .get_matches();
-
-    let config = Config {
-        input_path: PathBuf::from(matches.get_one::<String>("input").unwrap()),
-        target_size_kb: matches.get_one::<u64>("size").copied(),
-        dimensions: parse_dimensions(matches.get_one::<String>("dimensions")),
-        output_dir: matches.get_one::<String>("output").map(PathBuf::from),
-        maintain_aspect_ratio: matches.get_flag("maintain-ratio"),
-		auto_scale: matches.get_flag("auto-scale"),
-        parallel: matches.get_flag("parallel"),
-		verbose: matches.get_flag("verbose"),
-    };
-
-    process_images(&config)?;
-    Ok(())
-}
-
-fn parse_dimensions(dim_str: Option<&String>) -> Option<(u32, u32)> {
-    dim_str.and_then(|s| {
-        let parts: Vec<&str> = s.split('x').collect();
-        if parts.len() == 2 {
-            if let (Ok(width), Ok(height)) = (parts[0].parse(), parts[1].parse()) {
-                return Some((width, height));
-            }
-        }
-        None
-    })
-}
-
-fn process_images(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
-    let images = collect_images(&config.input_path)?;
-    
-    if images.is_empty() {
-        println!("❌ No image files found!");
-        return Ok(());
-    }
-
-    println!("📸 Found {} image(s) to process", images.len());
-    
-    let pb = ProgressBar::new(images.len() as u64);
-    pb.set_style(
-        ProgressStyle::default_bar()
-            .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta}) {msg}")?
-            .progress_chars("#>-"),
-    );
-
-    let results = Arc::new(Mutex::new(Vec::new()));
-    
-    if config.parallel {
-        let config = Arc::new(config.clone());
-        let pb = Arc::new(pb);
-        
-        images.par_iter().for_each(|image_path| {
-            let result = process_single_image_with_result(image_path, &config);
-            pb.inc(1);
-            
-            if let Some(file_name) = image_path.file_name() {
-                pb.set_message(format!("Processing: {}", file_name.to_string_lossy()));
-            }
-            
-            results.lock().unwrap().push(result);
-        });
-        
-        pb.finish_with_message("✨ Processing complete!");
-    } else {
-        for image_path in &images {
-            if let Some(file_name) = image_path.file_name() {
-                pb.set_message(format!("Processing: {}", file_name.to_string_lossy()));
-            }
-            
-            let result = process_single_image_with_result(image_path, config);
-            results.lock().unwrap().push(result);
-            pb.inc(1);
-        }
-        pb.finish_with_message("✨ Processing complete!");
-    }
-
-    // Print summary
-    println!("\n📊 Processing Summary:");
-    println!("{}", "─".repeat(60));
-    
-    let results = results.lock().unwrap();
-    let successful = results.iter().filter(|r| r.success).count();
-    let failed = results.len() - successful;
-    
-    let total_original: u64 = results.iter().map(|r| r.original_size).sum();
-    let total_final: u64 = results.iter().filter(|r| r.success).map(|r| r.final_size).sum();
-    let total_saved = total_original.saturating_sub(total_final);
-    
-    println!("✅ Successful: {}", successful);
-    println!("❌ Failed: {}", failed);
-	if successful > 0 {
-		println!("💾 Total saved: {} KB ({:.1}% reduction)", 
-			total_saved / 1024, 
-			(total_saved as f64 / total_original as f64) * 100.0
-		);
-	} else if failed > 0 {
-		println!("❌ Couldn't reach target file size, specify -c to auto scale image");
-	}
-    
-    if config.verbose {
-        println!("\n📋 Detailed Results:");
-        for result in results.iter() {
-            if result.success {
-                println!("  ✓ {} → {} ({} KB → {} KB) {}",
-                    result.input_path.file_name().unwrap().to_string_lossy(),
-                    result.output_path.file_name().unwrap().to_string_lossy(),
-                    result.original_size / 1024,
-                    result.final_size / 1024,
-                    result.message
-                );
-            } else {
-                println!("  ✗ {} - {}",
-                    result.input_path.file_name().unwrap().to_string_lossy(),
+        .arg(
+            Arg::new("dpi")
+                .long("dpi")
+                .value_name("N")
+                .help("Write resolution metadata in DPI (JPEG density, PNG pHYs)")
+                .value_parser(clap::value_parser!(u16).range(1..)),
+        )
+        .arg(
+            Arg::new("plan")
+                .long("plan")
+                .help("Estimate total output size from a sample of images without writing anything")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("collect-stats")
+                .long("collect-stats")
+                .help("Print histograms of input resolutions and file sizes, then exit without processing")
+                .action(clap::ArgAction::SetTrue)
+                .conflicts_with("plan"),
+        )
+        .arg(
+            Arg::new("no-write")
+                .long("no-write")
+                .help("Decode, resize and encode every image but discard the output, for benchmarking without disk IO")
+                .action(clap::ArgAction::SetTrue)
+                .conflicts_with_all([
+                    "plan",
+                    "overwrite-input",
+                    "keep-smaller",
+                    "validate-output",
+                    "preserve-permissions",
+                    "preserve-mtime",
+                    "copy-timestamps",
+                    "copy-unsupported",
+                    "resume",
+                    "report",
+                    "contact-sheet",
+                    "watch",
+                ]),
+        )
+        .arg(
+            Arg::new("to-srgb")
+                .long("to-srgb")
+                .help("Convert pixels from the embedded ICC profile to sRGB before encoding")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("stream-decode")
+                .long("stream-decode")
+                .help("Read PNG and TIFF inputs in rows or strips and shrink them while decoding, to cut peak memory")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("keep-smaller")
+                .long("keep-smaller")
+                .help("Keep the original file when re-encoding would make it larger")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("recompress-only-if-larger-than")
+                .long("recompress-only-if-larger-than")
+                .value_name("KB")
+                .help("Copy inputs of at most this many KB to the output unchanged instead of processing them")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .conflicts_with("raw-output"),
+        )
+        .arg(
+            Arg::new("validate-output")
+                .long("validate-output")
+                .help("Decode every written file again and fail images whose output is unreadable")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("post-command")
+                .long("post-command")
+                .value_name("CMD")
+                .help("Run CMD on each written output, with {} replaced by its path, e.g. \"oxipng -o 4 {}\"")
+                .value_parser(|s: &str| match shlex::split(s) {
+                    Some(words) if !words.is_empty() => Ok(words),
+                    _ => Err("expected a command with balanced quotes".to_string()),
+                })
+                .conflicts_with_all(["no-write", "plan"]),
+        )
+        .arg(
+            Arg::new("metrics")
+                .long("metrics")
+                .help("Measure SSIM and PSNR of each output against the original at the output size")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("ico-sizes")
+                .long("ico-sizes")
+                .value_name("SIZES")
+                .help("Write a multi-resolution ICO with the given sizes (e.g., 16,32,48)")
+                .value_parser(parse_ico_sizes),
+        )
+        .arg(
+            Arg::new("raw-input")
+                .long("raw-input")
+                .value_name("WIDTHxHEIGHT")
+                .help("Read .raw and .data inputs as headerless 8-bit RGBA pixels of this size")
+                .value_parser(|s: &str| {
+                    parse_dimensions(Some(&s.to_string()), None)
+                        .ok()
+                        .flatten()
+                        .filter(|&(width, height)| width > 0 && height > 0)
+                        .ok_or_else(|| format!("invalid size '{}', expected WIDTHxHEIGHT", s))
+                }),
+        )
+        .arg(
+            Arg::new("raw-output")
+                .long("raw-output")
+                .help("Write outputs as headerless 8-bit RGBA pixels with a .raw extension")
+                .action(clap::ArgAction::SetTrue)
+                .conflicts_with_all([
+                    "size",
+                    "ico-sizes",
+                    "animated-webp",
+                    "keep-smaller",
+                    "overwrite-input",
+                    "validate-output",
+                    "metrics",
+                    "contact-sheet",
+                ]),
+        )
+        .arg(
+            Arg::new("profile")
+                .long("profile")
+                .help("Time each processing stage and print the totals in the summary")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("group-by-directory")
+                .long("group-by-directory")
+                .help("Break the summary down by each input's parent directory, most savings first")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("summary-threshold")
+                .long("summary-threshold")
+                .value_name("PERCENT")
+                .help("List the files that shrank by less than PERCENT in the summary, worst first")
+                .value_parser(clap::value_parser!(f64)),
+        )
+        .arg(
+            Arg::new("name-template")
+                .long("name-template")
+                .value_name("TEMPLATE")
+                .help("Output file name template, e.g. {stem}_{w}x{h}_q{quality}.{ext}")
+                .value_parser(parse_name_template),
+        )
+        .arg(
+            Arg::new("output-suffix-none-when-converting")
+                .long("output-suffix-none-when-converting")
+                .help("Leave out the _resized suffix when the output extension differs from the input")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("no-default-suffix-when-output-dir")
+                .long("no-default-suffix-when-output-dir")
+                .help("Leave out the _resized suffix for files written to an --output folder other than their own")
+                .requires("output")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("strip-resized-suffix-on-input")
+                .long("strip-resized-suffix-on-input")
+                .help("Skip earlier _resized outputs found in the input and never stack the suffix twice")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("date-prefix")
+                .long("date-prefix")
+                .help("Prefix output file names with the EXIF capture date as YYYYMMDD_")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("date-fallback")
+                .long("date-fallback")
+                .value_name("FALLBACK")
+                .help("Date used by --date-prefix without EXIF: file modification time (mtime) or no prefix (none)")
+                .value_parser(["mtime", "none"])
+                .default_value("mtime")
+                .requires("date-prefix"),
+        )
+        .arg(
+            Arg::new("check-exif-dimensions")
+                .long("check-exif-dimensions")
+                .help("Warn when EXIF PixelXDimension/PixelYDimension disagree with the decoded size")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("two-pass")
+                .long("two-pass")
+                .help("Re-encode at neighboring qualities to land closer to the size target")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("sample-pixels")
+                .long("sample-pixels")
+                .help("Estimate the quality on a half-size proxy first so the full-size search needs fewer encodes")
+                .action(clap::ArgAction::SetTrue)
+                .requires("size"),
+        )
+        .arg(
+            Arg::new("subsampling")
+                .long("subsampling")
+                .value_name("MODE")
+                .help("JPEG chroma subsampling (default: 4:4:4, relaxed to 4:2:0 to meet a size target)")
+                .value_parser(["444", "422", "420"]),
+        )
+        .arg(
+            Arg::new("optimize")
+                .long("optimize")
+                .help("Build optimized Huffman tables for smaller JPEGs at the same quality (slower)")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("webp-effort")
+                .long("webp-effort")
+                .value_name("0-6")
+                .help("WebP encoder effort; higher is slower but smaller (default: 4)")
+                .value_parser(clap::value_parser!(u8).range(0..=6))
+                .default_value("4"),
+        )
+        .arg(
+            Arg::new("encode-threads")
+                .long("encode-threads")
+                .value_name("N")
+                .help("Threads each encoder may use, on top of --parallel's file-level threads (default: 1)")
+                .value_parser(clap::value_parser!(u32).range(1..))
+                .default_value("1"),
+        )
+        .arg(
+            Arg::new("webp-lossy")
+                .long("webp-lossy")
+                .help("Encode lossless WebP inputs lossily instead of keeping them lossless")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("near-lossless")
+                .long("near-lossless")
+                .value_name("LEVEL")
+                .help("Encode WebP outputs near-lossless: 100 is lossless, lower values allow more pixel changes for smaller files")
+                .value_parser(clap::value_parser!(u8).range(0..=100))
+                .conflicts_with("webp-lossy"),
+        )
+        .arg(
+            Arg::new("output-bit-depth")
+                .long("output-bit-depth")
+                .value_name("BITS")
+                .help("Force PNG output bit depth; 1, 2 and 4 require a grayscale image")
+                .value_parser(["1", "2", "4", "8", "16"]),
+        )
+        .arg(
+            Arg::new("quality-curve")
+                .long("quality-curve")
+                .value_name("CURVE")
+                .help("Map PNG quality to compression level, filter and palette size, as QUALITY:LEVEL:FILTER:COLORS,...")
+                .num_args(0..=1)
+                .default_missing_value(DEFAULT_QUALITY_CURVE)
+                .value_parser(parse_quality_curve)
+                .conflicts_with("output-bit-depth"),
+        )
+        .arg(
+            Arg::new("format-quality")
+                .long("format-quality")
+                .value_name("FORMAT=QUALITY,...")
+                .help("Quality per output format when there is no --size, e.g. jpeg=82,webp=78 (default: 90 for both)")
+                .value_parser(parse_format_quality)
+                .conflicts_with("size"),
+        )
+        .arg(
+            Arg::new("force-rgb")
+                .long("force-rgb")
+                .help("Always write RGB, flattening any transparency onto --background")
+                .conflicts_with_all(["force-rgba", "output-bit-depth"])
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("force-rgba")
+                .long("force-rgba")
+                .help("Always write RGBA; JPEG output is flattened onto --background instead")
+                .conflicts_with("output-bit-depth")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("transparent-to-white")
+                .long("transparent-to-white")
+                .help("Flatten transparency onto white before encoding, instead of letting JPEG turn it black")
+                .conflicts_with_all(["background", "force-rgba", "raw-output"])
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("watch")
+                .long("watch")
+                .help("Keep running and process new images as they appear in the input directory")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("exclude-dir")
+                .long("exclude-dir")
+                .value_name("NAME")
+                .help("Skip subdirectories with this name (repeatable)")
+                .action(clap::ArgAction::Append),
+        )
+        .arg(
+            Arg::new("copy-unsupported")
+                .long("copy-unsupported")
+                .help("Copy files that aren't collected as images unchanged to the output directory")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("resume")
+                .long("resume")
+                .value_name("STATE")
+                .help("Record completed inputs in this file and skip them on later runs"),
+        )
+        .arg(
+            Arg::new("report")
+                .long("report")
+                .value_name("FILE")
+                .help("Write a JSON report with the summary and per-file results"),
+        )
+        .arg(
+            Arg::new("keep-going-report")
+                .long("keep-going-report")
+                .value_name("FILE")
+                .help("Write the paths of failed images to FILE (default: failures.txt), one per line, for --stdin-list")
+                .num_args(0..=1)
+                .default_missing_value("failures.txt"),
+        )
+        .arg(
+            Arg::new("error-log")
+                .long("error-log")
+                .value_name("FILE")
+                .help("Write the full error chain of every failed image to FILE, including the decoder's own error"),
+        )
+        .arg(
+            Arg::new("pretty")
+                .long("pretty")
+                .help("Indent the --report JSON for reading instead of writing it compactly")
+                .action(clap::ArgAction::SetTrue)
+                .requires("report"),
+        )
+        .arg(
+            Arg::new("contact-sheet")
+                .long("contact-sheet")
+                .value_name("FILE")
+                .help("Also write a grid of captioned thumbnails of every output to this image"),
+        )
+        .arg(
+            Arg::new("columns")
+                .long("columns")
+                .value_name("N")
+                .help("Thumbnails per row on the contact sheet (default: 5)")
+                .value_parser(clap::value_parser!(u32).range(1..))
+                .default_value("5")
+                .requires("contact-sheet"),
+        )
+        .arg(
+            Arg::new("hash-algorithm")
+                .long("hash-algorithm")
+                .value_name("ALGORITHM")
+                .help("Record a hash of each output in the report (blake3, sha256 or xxhash)")
+                .value_parser(["blake3", "sha256", "xxhash"])
+                .requires("report"),
+        )
+        .arg(
+            Arg::new("input-formats")
+                .long("input-formats")
+                .value_name("EXTS")
+                .help("Only collect files with these extensions (e.g., jpg,png)")
+                .value_delimiter(','),
+        )
+        .arg(
+            Arg::new("shuffle")
+                .long("shuffle")
+                .help("Randomize the order of collected images, e.g. to sample a large tree with --limit")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("seed")
+                .long("seed")
+                .value_name("N")
+                .help("Seed for --shuffle so the same order can be reproduced")
+                .value_parser(clap::value_parser!(u64))
+                .requires("shuffle"),
+        )
+        .arg(
+            Arg::new("limit")
+                .long("limit")
+                .value_name("N")
+                .help("Process at most N of the collected images")
+                .value_parser(clap::value_parser!(u64).range(1..)),
+        )
+        .arg(
+            Arg::new("max-files")
+                .long("max-files")
+                .value_name("N")
+                .help("Abort instead of processing more than N images (default: 10000)")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .default_value("10000"),
+        )
+        .arg(
+            Arg::new("no-limit")
+                .long("no-limit")
+                .help("Disable the --max-files safety cap")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("comment")
+                .long("comment")
+                .value_name("TEXT")
+                .help("Embed a text comment (e.g. copyright) in each output file"),
+        )
+        .arg(
+            Arg::new("flatten-gif-to-png")
+                .long("flatten-gif-to-png")
+                .help("Save the first frame (or the --frame) of GIF inputs as a static PNG")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("animated-webp")
+                .long("animated-webp")
+                .help("Save animated GIF and WebP inputs as animated WebP, resizing every frame")
+                .action(clap::ArgAction::SetTrue)
+                .conflicts_with_all(["flatten-gif-to-png", "ico-sizes"]),
+        )
+        .arg(
+            Arg::new("skip-animated")
+                .long("skip-animated")
+                .help("Leave multi-frame GIF and WebP inputs alone instead of saving only their first frame")
+                .action(clap::ArgAction::SetTrue)
+                .conflicts_with_all(["flatten-gif-to-png", "animated-webp"]),
+        )
+        .arg(
+            Arg::new("frame")
+                .long("frame")
+                .value_name("N")
+                .help("Process frame N (counting from 0) of animated GIF and WebP inputs instead of the first")
+                .value_parser(clap::value_parser!(usize))
+                .conflicts_with_all(["animated-webp", "skip-animated"]),
+        )
+        .arg(
+            Arg::new("min-dimensions")
+                .long("min-dimensions")
+                .alias("exclude-smaller-than-dimensions")
+                .value_name("WIDTHxHEIGHT")
+                .help("Leave images narrower or shorter than this alone, e.g. icons and sprites")
+                .value_parser(|s: &str| {
+                    parse_dimensions(Some(&s.to_string()), None)
+                        .ok()
+                        .flatten()
+                        .ok_or_else(|| format!("invalid size '{}', expected WIDTHxHEIGHT", s))
+                }),
+        )
+        .arg(
+            Arg::new("min-quality")
+                .long("min-quality")
+                .value_name("QUALITY")
+                .help("Lowest quality the size search may use (default: 10)")
+                .env("IMAGE_RESIZER_MIN_QUALITY")
+                .value_parser(clap::value_parser!(u8).range(1..=100))
+                .default_value("10"),
+        )
+        .arg(
+            Arg::new("max-quality")
+                .long("max-quality")
+                .value_name("QUALITY")
+                .help("Highest quality the size search may use (default: 95)")
+                .env("IMAGE_RESIZER_MAX_QUALITY")
+                .value_parser(clap::value_parser!(u8).range(1..=100))
+                .default_value("95"),
+        )
+        .arg(
+            Arg::new("warn-below-quality")
+                .long("warn-below-quality")
+                .value_name("QUALITY")
+                .help("Warn about lossy outputs encoded below this quality (default: 50)")
+                .value_parser(clap::value_parser!(u8).range(1..=100))
+                .default_value("50"),
+        )
+        .arg(
+            Arg::new("prefer")
+                .long("prefer")
+                .value_name("STRATEGY")
+                .help("Lower quality before scaling (quality), scale before lowering quality (scale), or trade both (balanced)")
+                .env("IMAGE_RESIZER_PREFER")
+                .value_parser(["quality", "scale", "balanced"])
+                .default_value("quality"),
+        )
+        .arg(
+            Arg::new("force-format-on-failure")
+                .long("force-format-on-failure")
+                .value_name("FORMAT")
+                .help("Convert images that can't reach --size in their own format to this lossy format")
+                .value_parser(["jpeg", "webp"])
+                .requires("size"),
+        )
+        .arg(
+            Arg::new("summary-json")
+                .long("summary-json")
+                .help("Print the batch summary as a single JSON object instead of the decorated summary")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("progress-json")
+                .long("progress-json")
+                .help("Stream start, per-file and done events as JSON lines on stderr; implies --quiet")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("print-config")
+                .long("print-config")
+                .help("Print the resolved settings as JSON and exit without processing")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("quiet")
+                .long("quiet")
+                .short('q')
+                .help("Suppress the progress bar and summary output")
+                .action(clap::ArgAction::SetTrue),
+        )
+}
+
+// Settings for one run from the parsed arguments, validated
+fn config_from_matches(matches: &clap::ArgMatches) -> Result<Config, Box<dyn std::error::Error>> {
+    // An -o with an image extension that isn't an existing folder names the output file itself
+    let output = matches.get_one::<String>("output").map(PathBuf::from);
+    let output_file = output.clone().filter(|path| !path.is_dir() && get_image_format(path).is_ok());
+
+    // A name with glob characters is still taken literally when such a file or folder exists
+    let input = matches.get_one::<String>("input").map_or(".", String::as_str);
+    let input_glob = (is_glob(input) && !Path::new(input).exists()).then(|| input.to_string());
+
+    let config = Config {
+        // Listed paths are taken as given, so relative ones resolve against the working directory
+        input_path: match &input_glob {
+            Some(pattern) => glob_base(pattern),
+            None => PathBuf::from(input),
+        },
+        input_glob,
+        stdin_list: matches.get_flag("stdin-list"),
+        target_size_kb: matches.get_one::<u64>("size").copied(),
+        size_tolerance: matches.get_one::<f64>("size-tolerance").copied(),
+        dimensions: parse_dimensions(matches.get_one::<String>("dimensions"), matches.get_one::<u16>("dpi").copied())?,
+        megapixels: matches.get_one::<f64>("megapixels").copied(),
+        edge: match (matches.get_one::<u32>("long-edge"), matches.get_one::<u32>("short-edge")) {
+            (Some(&pixels), _) => Some(Edge::Long(pixels)),
+            (_, Some(&pixels)) => Some(Edge::Short(pixels)),
+            _ => None,
+        },
+        allow_upscale: matches.get_flag("allow-upscale"),
+        round_dimensions: matches.get_one::<u32>("round-dimensions").copied(),
+        normalize_orientation: matches.get_one::<String>("normalize-orientation").map(|s| match s.as_str() {
+            "portrait" => Orientation::Portrait,
+            _ => Orientation::Landscape,
+        }),
+        trim: matches
+            .get_flag("trim")
+            .then(|| *matches.get_one::<u8>("trim-tolerance").unwrap()),
+        fail_on_upscale: matches.get_flag("fail-on-upscale"),
+        downscale_only: matches.get_flag("downscale-only"),
+        resampling: Resampling {
+            fast_thumbnails: matches.get_flag("fast-thumbnails"),
+            lanczos_window: *matches.get_one::<u32>("lanczos-window").unwrap(),
+        },
+        letterbox: matches.get_flag("letterbox"),
+        equal_output_size: matches.get_one::<(u32, u32)>("equal-output-size").copied(),
+        equal_output_fit: match matches.get_one::<String>("equal-output-fit").map(String::as_str) {
+            Some("crop") => EqualFit::Crop,
+            _ => EqualFit::Pad,
+        },
+        background: *matches.get_one::<image::Rgba<u8>>("background").unwrap(),
+        palette: match matches.get_one::<String>("palette-from") {
+            Some(path) => Some(load_palette(Path::new(path))?),
+            None => None,
+        },
+        dither: matches.get_flag("dither"),
+        output_dir: match &output_file {
+            Some(file) => Some(file.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new(".")).to_path_buf()),
+            None => output,
+        },
+        output_file,
+        overwrite_input: matches.get_flag("overwrite-input"),
+        output_temp_dir: matches.get_one::<PathBuf>("output-temp-dir").cloned(),
+        preserve_permissions: matches.get_flag("preserve-permissions"),
+        preserve_mtime: matches.get_flag("preserve-mtime"),
+        copy_timestamps: matches.get_flag("copy-timestamps"),
+        maintain_aspect_ratio: matches.get_flag("maintain-ratio"),
+		auto_scale: matches.get_flag("auto-scale"),
+        auto_scale_min: *matches.get_one::<u8>("auto-scale-min").unwrap(),
+        auto_scale_start: *matches.get_one::<f32>("auto-scale-start").unwrap(),
+        auto_scale_step: *matches.get_one::<f32>("auto-scale-step").unwrap(),
+        parallel: matches.get_flag("parallel"),
+        gpu: matches.get_flag("gpu"),
+		verbose: matches.get_flag("verbose"),
+        dpi: matches.get_one::<u16>("dpi").copied(),
+        plan: matches.get_flag("plan"),
+        collect_stats: matches.get_flag("collect-stats"),
+        no_write: matches.get_flag("no-write"),
+        to_srgb: matches.get_flag("to-srgb"),
+        stream_decode: matches.get_flag("stream-decode"),
+        keep_smaller: matches.get_flag("keep-smaller"),
+        recompress_above_kb: matches.get_one::<u64>("recompress-only-if-larger-than").copied(),
+        validate_output: matches.get_flag("validate-output"),
+        post_command: matches.get_one::<Vec<String>>("post-command").cloned(),
+        metrics: matches.get_flag("metrics"),
+        ico_sizes: matches.get_one::<Vec<u32>>("ico-sizes").cloned(),
+        raw_input: matches.get_one::<(u32, u32)>("raw-input").copied(),
+        raw_output: matches.get_flag("raw-output"),
+        profile: matches.get_flag("profile"),
+        group_by_directory: matches.get_flag("group-by-directory"),
+        summary_threshold: matches.get_one::<f64>("summary-threshold").copied(),
+        name_template: matches.get_one::<Vec<TemplatePart>>("name-template").cloned(),
+        no_suffix_when_converting: matches.get_flag("output-suffix-none-when-converting"),
+        no_suffix_in_output_dir: matches.get_flag("no-default-suffix-when-output-dir"),
+        strip_resized_suffix: matches.get_flag("strip-resized-suffix-on-input"),
+        date_prefix: matches.get_flag("date-prefix"),
+        date_fallback: match matches.get_one::<String>("date-fallback").map(String::as_str) {
+            Some("none") => DateFallback::Skip,
+            _ => DateFallback::Mtime,
+        },
+        check_exif_dimensions: matches.get_flag("check-exif-dimensions"),
+        two_pass: matches.get_flag("two-pass"),
+        sample_pixels: matches.get_flag("sample-pixels"),
+        subsampling: matches.get_one::<String>("subsampling").map(|s| match s.as_str() {
+            "422" => Subsampling::S422,
+            "420" => Subsampling::S420,
+            _ => Subsampling::S444,
+        }),
+        optimize: matches.get_flag("optimize"),
+        webp_effort: *matches.get_one::<u8>("webp-effort").unwrap(),
+        encode_threads: *matches.get_one::<u32>("encode-threads").unwrap(),
+        webp_lossy: matches.get_flag("webp-lossy"),
+        near_lossless: matches.get_one::<u8>("near-lossless").copied(),
+        webp_lossless: false,
+        png_bit_depth: matches.get_one::<String>("output-bit-depth").map(|s| s.parse().unwrap()),
+        quality_curve: matches.get_one::<Vec<CurvePoint>>("quality-curve").cloned(),
+        transparent_to_white: matches.get_flag("transparent-to-white"),
+        force_color: if matches.get_flag("force-rgb") {
+            Some(ForceColor::Rgb)
+        } else if matches.get_flag("force-rgba") {
+            Some(ForceColor::Rgba)
+        } else {
+            None
+        },
+        watch: matches.get_flag("watch"),
+        exclude_dirs: matches
+            .get_many::<String>("exclude-dir")
+            .map(|dirs| dirs.cloned().collect())
+            .unwrap_or_default(),
+        copy_unsupported: matches.get_flag("copy-unsupported"),
+        resume: matches.get_one::<String>("resume").map(PathBuf::from),
+        report: matches.get_one::<String>("report").map(PathBuf::from),
+        failures_list: matches.get_one::<String>("keep-going-report").map(PathBuf::from),
+        error_log: matches.get_one::<String>("error-log").map(PathBuf::from),
+        pretty: matches.get_flag("pretty"),
+        contact_sheet: matches.get_one::<String>("contact-sheet").map(PathBuf::from),
+        columns: *matches.get_one::<u32>("columns").unwrap(),
+        hash_algorithm: matches.get_one::<String>("hash-algorithm").map(|s| match s.as_str() {
+            "sha256" => HashAlgorithm::Sha256,
+            "xxhash" => HashAlgorithm::XxHash,
+            _ => HashAlgorithm::Blake3,
+        }),
+        input_formats: matches.get_many::<String>("input-formats").map(|exts| {
+            exts.map(|ext| ext.trim().trim_start_matches('.').to_lowercase()).collect()
+        }),
+        shuffle: matches.get_flag("shuffle"),
+        seed: matches.get_one::<u64>("seed").copied(),
+        limit: matches.get_one::<u64>("limit").map(|&n| n as usize),
+        max_files: (!matches.get_flag("no-limit")).then(|| *matches.get_one::<u64>("max-files").unwrap() as usize),
+        over_max_files: matches.get_flag("yes"),
+        comment: matches.get_one::<String>("comment").cloned(),
+        flatten_gif: matches.get_flag("flatten-gif-to-png"),
+        animated_webp: matches.get_flag("animated-webp"),
+        frame: matches.get_one::<usize>("frame").copied(),
+        skip_animated: matches.get_flag("skip-animated"),
+        min_dimensions: matches.get_one::<(u32, u32)>("min-dimensions").copied(),
+        min_quality: *matches.get_one::<u8>("min-quality").unwrap(),
+        max_quality: *matches.get_one::<u8>("max-quality").unwrap(),
+        warn_below_quality: *matches.get_one::<u8>("warn-below-quality").unwrap(),
+        format_quality: matches.get_one::<FormatQuality>("format-quality").copied().unwrap_or_default(),
+        prefer: match matches.get_one::<String>("prefer").map(String::as_str) {
+            Some("scale") => Prefer::Scale,
+            Some("balanced") => Prefer::Balanced,
+            _ => Prefer::Quality,
+        },
+        fallback_format: matches.get_one::<String>("force-format-on-failure").map(|s| match s.as_str() {
+            "webp" => ImageFormat::WebP,
+            _ => ImageFormat::Jpeg,
+        }),
+        summary_json: matches.get_flag("summary-json"),
+        progress_json: matches.get_flag("progress-json"),
+        // A wrapper reading the event stream has no use for the decorated output
+        quiet: matches.get_flag("quiet") || matches.get_flag("progress-json"),
+    };
+
+    if config.size_tolerance.is_some_and(|percent| !(0.0..100.0).contains(&percent)) {
+        return Err("--size-tolerance must be at least 0 and below 100".into());
+    }
+
+    if !(config.auto_scale_start > 0.0 && config.auto_scale_start < 1.0) {
+        return Err("--auto-scale-start must be between 0 and 1, exclusive".into());
+    }
+
+    if !(config.auto_scale_step > 0.0 && config.auto_scale_step < 1.0) {
+        return Err("--auto-scale-step must be between 0 and 1, exclusive".into());
+    }
+
+    if config.summary_threshold.is_some_and(|percent| !percent.is_finite()) {
+        return Err("--summary-threshold must be a number".into());
+    }
+
+    if config.megapixels.is_some_and(|mp| !mp.is_finite() || mp <= 0.0) {
+        return Err("--megapixels must be greater than 0".into());
+    }
+
+    if config.comment.as_ref().is_some_and(|c| c.len() > MAX_JPEG_COMMENT_LEN) {
+        return Err(format!("--comment is limited to {} bytes", MAX_JPEG_COMMENT_LEN).into());
+    }
+
+    if config.min_quality > config.max_quality {
+        return Err("--min-quality cannot be greater than --max-quality".into());
+    }
+
+    if config.overwrite_input && !matches.get_flag("yes") {
+        return Err("--overwrite-input replaces your original files and cannot be undone; add --yes to confirm".into());
+    }
+
+    // Scaling for the size target would break the uniform size
+    if config.equal_output_size.is_some() && config.prefer != Prefer::Quality {
+        return Err("--equal-output-size keeps every output at one size, so it can't be combined with --prefer scale or balanced".into());
+    }
+
+    if let Some(dir) = &config.output_temp_dir {
+        if !dir.is_dir() {
+            return Err(format!("--output-temp-dir {} is not a directory", dir.display()).into());
+        }
+    }
+
+    if let Some(pattern) = &config.input_glob {
+        if let Err(e) = glob::Pattern::new(pattern) {
+            return Err(format!("Invalid --input pattern '{}': {}", pattern, e).into());
+        }
+        if config.watch {
+            return Err("--watch requires a directory as input, not a pattern".into());
+        }
+    }
+
+    if config.watch && !config.input_path.is_dir() {
+        return Err("--watch requires a directory as input".into());
+    }
+
+    if let Some(output_file) = &config.output_file {
+        if config.stdin_list || config.input_path.is_dir() {
+            return Err(format!(
+                "--output {} is a file, but the input is a directory or list of images; pass a directory to --output",
+                output_file.display()
+            )
+            .into());
+        }
+        if config.name_template.is_some() || config.date_prefix {
+            return Err("--name-template and --date-prefix name files inside an output directory, not an --output file".into());
+        }
+    }
+
+    Ok(config)
+}
+
+// Pixels, or print sizes such as 4inx6in or 10cmx15cm converted to pixels at `dpi`
+fn parse_dimensions(dim_str: Option<&String>, dpi: Option<u16>) -> Result<Option<(u32, u32)>, String> {
+    let Some(s) = dim_str else {
+        return Ok(None);
+    };
+    let parts: Vec<&str> = s.split('x').collect();
+    if parts.len() == 2 {
+        if let (Some(width), Some(height)) = (parse_length(parts[0], dpi, s)?, parse_length(parts[1], dpi, s)?) {
+            return Ok(Some((width, height)));
+        }
+    }
+    Ok(None)
+}
+
+// Steps are QUALITY:LEVEL:FILTER:COLORS separated by commas, in any order
+fn parse_quality_curve(curve: &str) -> Result<Vec<CurvePoint>, String> {
+    let mut points = curve
+        .split(',')
+        .map(|step| {
+            let fields: Vec<&str> = step.trim().split(':').collect();
+            let [quality, compression, filter, colors] = fields[..] else {
+                return Err(format!("invalid curve step '{}', expected QUALITY:LEVEL:FILTER:COLORS", step));
+            };
+            let quality = quality
+                .parse()
+                .ok()
+                .filter(|quality| (1..=100).contains(quality))
+                .ok_or_else(|| format!("invalid quality '{}' in curve, expected 1-100", quality))?;
+            let compression = match compression {
+                "fast" => PngCompression::Fast,
+                "default" => PngCompression::Default,
+                "best" => PngCompression::Best,
+                _ => return Err(format!("invalid compression level '{}' in curve, expected fast, default or best", compression)),
+            };
+            let filter = match filter {
+                "none" => PngFilter::None,
+                "sub" => PngFilter::Sub,
+                "up" => PngFilter::Up,
+                "avg" => PngFilter::Avg,
+                "paeth" => PngFilter::Paeth,
+                "adaptive" => PngFilter::Adaptive,
+                _ => return Err(format!("invalid filter '{}' in curve, expected none, sub, up, avg, paeth or adaptive", filter)),
+            };
+            let colors = colors
+                .parse()
+                .ok()
+                .filter(|colors| *colors == 0 || (2..=256).contains(colors))
+                .ok_or_else(|| format!("invalid palette size '{}' in curve, expected 0 or 2-256", colors))?;
+            Ok(CurvePoint { quality, compression, filter, colors })
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    // Highest first, so the first step at or below a quality is the one that applies
+    points.sort_by_key(|point| std::cmp::Reverse(point.quality));
+    if let Some(pair) = points.windows(2).find(|pair| pair[0].quality == pair[1].quality) {
+        return Err(format!("quality {} appears twice in curve", pair[0].quality));
+    }
+    Ok(points)
+}
+
+// Comma-separated FORMAT=QUALITY pairs; formats left out keep the default
+fn parse_format_quality(list: &str) -> Result<FormatQuality, String> {
+    let mut qualities = FormatQuality::default();
+    for pair in list.split(',') {
+        let (format, quality) = pair
+            .trim()
+            .split_once('=')
+            .ok_or_else(|| format!("invalid entry '{}', expected FORMAT=QUALITY", pair))?;
+        let quality = quality
+            .parse()
+            .ok()
+            .filter(|quality| (1..=100).contains(quality))
+            .ok_or_else(|| format!("invalid quality '{}' for {}, expected 1-100", quality, format))?;
+        match format.to_lowercase().as_str() {
+            "jpeg" | "jpg" => qualities.jpeg = Some(quality),
+            "webp" => qualities.webp = Some(quality),
+            "png" => qualities.png = Some(quality),
+            _ => return Err(format!("invalid format '{}', expected jpeg, webp or png", format)),
+        }
+    }
+    Ok(qualities)
+}
+
+// Qualities below the lowest step use that step too
+fn curve_point(curve: &[CurvePoint], quality: u8) -> CurvePoint {
+    *curve
+        .iter()
+        .find(|point| point.quality <= quality)
+        .unwrap_or_else(|| curve.last().expect("curve has at least one step"))
+}
+
+fn parse_length(length: &str, dpi: Option<u16>, dim_str: &str) -> Result<Option<u32>, String> {
+    let (number, inches_per_unit) = match length {
+        _ if length.ends_with("in") => (&length[..length.len() - 2], 1.0),
+        _ if length.ends_with("cm") => (&length[..length.len() - 2], 1.0 / 2.54),
+        _ if length.ends_with("mm") => (&length[..length.len() - 2], 1.0 / 25.4),
+        _ => return Ok(length.parse().ok()),
+    };
+    let Some(dpi) = dpi else {
+        return Err(format!("dimensions '{}' use physical units, which need --dpi to convert to pixels", dim_str));
+    };
+    Ok(number
+        .parse::<f64>()
+        .ok()
+        .filter(|value| value.is_finite() && *value > 0.0)
+        .map(|value| (value * inches_per_unit * f64::from(dpi)).round() as u32))
+}
+
+fn parse_color(s: &str) -> Result<image::Rgba<u8>, String> {
+    let hex = s.trim_start_matches('#');
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16);
+    let parsed = match hex.len() {
+        6 if hex.is_ascii() => (channel(0), channel(2), channel(4), Ok(255)),
+        8 if hex.is_ascii() => (channel(0), channel(2), channel(4), channel(6)),
+        _ => return Err(format!("invalid color '{}', expected RRGGBB or RRGGBBAA", s)),
+    };
+    match parsed {
+        (Ok(r), Ok(g), Ok(b), Ok(a)) => Ok(image::Rgba([r, g, b, a])),
+        _ => Err(format!("invalid color '{}', expected RRGGBB or RRGGBBAA", s)),
+    }
+}
+
+fn format_color(color: &image::Rgba<u8>) -> String {
+    let [r, g, b, a] = color.0;
+    if a == 255 {
+        format!("#{:02x}{:02x}{:02x}", r, g, b)
+    } else {
+        format!("#{:02x}{:02x}{:02x}{:02x}", r, g, b, a)
+    }
+}
+
+fn serialize_color<S: serde::Serializer>(color: &image::Rgba<u8>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format_color(color))
+}
+
+fn serialize_format<S: serde::Serializer>(format: &Option<ImageFormat>, serializer: S) -> Result<S::Ok, S::Error> {
+    match format {
+        Some(format) => serializer.serialize_some(format_extension(*format)),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn serialize_palette<S: serde::Serializer>(palette: &Option<Palette>, serializer: S) -> Result<S::Ok, S::Error> {
+    match palette {
+        Some(palette) => serializer.collect_seq(
+            palette
+                .colors
+                .iter()
+                .map(|&[r, g, b]| format_color(&image::Rgba([r, g, b, 255]))),
+        ),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn load_palette(path: &Path) -> Result<Palette, Box<dyn std::error::Error>> {
+    let extension = path.extension().map(|e| e.to_string_lossy().to_lowercase());
+    let colors = match extension.as_deref() {
+        Some("gpl") => parse_gpl(&fs::read_to_string(path)?)?,
+        Some("act") => parse_act(&fs::read(path)?)?,
+        _ => {
+            let mut colors = BTreeSet::new();
+            for pixel in image::open(path)?.to_rgb8().pixels() {
+                colors.insert(pixel.0);
+                if colors.len() > MAX_PALETTE_COLORS {
+                    return Err(format!("palette image {} has more than {} colors", path.display(), MAX_PALETTE_COLORS).into());
+                }
+            }
+            colors.into_iter().collect()
+        }
+    };
+    if colors.is_empty() {
+        return Err(format!("palette {} has no colors", path.display()).into());
+    }
+    Ok(Palette { colors })
+}
+
+// GIMP palette: a "GIMP Palette" header, optional Name/Columns lines and "#" comments,
+// then one "R G B [name]" line per color
+fn parse_gpl(contents: &str) -> Result<Vec<[u8; 3]>, String> {
+    let mut lines = contents.lines();
+    if lines.next().map(str::trim) != Some("GIMP Palette") {
+        return Err("not a GIMP palette, missing 'GIMP Palette' header".to_string());
+    }
+    lines
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#') && !line.contains(':'))
+        .map(|line| {
+            let channels: Vec<u8> = line.split_whitespace().take(3).filter_map(|v| v.parse().ok()).collect();
+            match channels[..] {
+                [r, g, b] => Ok([r, g, b]),
+                _ => Err(format!("invalid palette line '{}'", line)),
+            }
+        })
+        .collect()
+}
+
+// Adobe color table: 256 RGB triplets, optionally followed by a big-endian color count
+// and transparent index
+fn parse_act(data: &[u8]) -> Result<Vec<[u8; 3]>, String> {
+    if data.len() != 768 && data.len() != 772 {
+        return Err(format!("invalid .act palette, expected 768 or 772 bytes, got {}", data.len()));
+    }
+    let count = match data.get(768..770) {
+        Some(&[hi, lo]) if u16::from_be_bytes([hi, lo]) > 0 => usize::from(u16::from_be_bytes([hi, lo])).min(256),
+        _ => 256,
+    };
+    Ok(data[..count * 3].chunks_exact(3).map(|c| [c[0], c[1], c[2]]).collect())
+}
+
+fn parse_ico_sizes(s: &str) -> Result<Vec<u32>, String> {
+    s.split(',')
+        .map(|part| match part.trim().parse::<u32>() {
+            Ok(size) if (1..=256).contains(&size) => Ok(size),
+            _ => Err(format!("invalid icon size '{}', expected 1-256", part.trim())),
+        })
+        .collect()
+}
+
+fn parse_name_template(s: &str) -> Result<Vec<TemplatePart>, String> {
+    let mut parts = Vec::new();
+    let mut rest = s;
+
+    while let Some(start) = rest.find('{') {
+        if start > 0 {
+            parts.push(TemplatePart::Literal(rest[..start].to_string()));
+        }
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("unclosed '{{' in template '{}'", s))?;
+        let part = match &rest[start + 1..start + end] {
+            "stem" => TemplatePart::Stem,
+            "w" | "width" => TemplatePart::Width,
+            "h" | "height" => TemplatePart::Height,
+            "quality" => TemplatePart::Quality,
+            "scale" => TemplatePart::Scale,
+            "format" => TemplatePart::Format,
+            "index" => TemplatePart::Index,
+            "ext" => TemplatePart::Ext,
+            token => {
+                return Err(format!(
+                    "unknown token '{{{}}}', expected one of stem, w, h, width, height, quality, scale, format, index, ext",
+                    token
+                ))
+            }
+        };
+        parts.push(part);
+        rest = &rest[start + end + 1..];
+    }
+
+    if rest.contains('}') {
+        return Err(format!("unmatched '}}' in template '{}'", s));
+    }
+    if !rest.is_empty() {
+        parts.push(TemplatePart::Literal(rest.to_string()));
+    }
+    if parts.is_empty() {
+        return Err("template must not be empty".to_string());
+    }
+
+    Ok(parts)
+}
+
+// Rebuilds the --name-template string so --print-config shows what was typed
+fn serialize_template<S: serde::Serializer>(template: &Option<Vec<TemplatePart>>, serializer: S) -> Result<S::Ok, S::Error> {
+    let Some(parts) = template else {
+        return serializer.serialize_none();
+    };
+    let text: String = parts
+        .iter()
+        .map(|part| match part {
+            TemplatePart::Literal(text) => text.as_str(),
+            TemplatePart::Stem => "{stem}",
+            TemplatePart::Width => "{width}",
+            TemplatePart::Height => "{height}",
+            TemplatePart::Quality => "{quality}",
+            TemplatePart::Scale => "{scale}",
+            TemplatePart::Format => "{format}",
+            TemplatePart::Index => "{index}",
+            TemplatePart::Ext => "{ext}",
+        })
+        .collect();
+    serializer.serialize_some(&text)
+}
+
+fn process_images(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let files = match config.stdin_list {
+        true => read_stdin_list(config)?,
+        false => collect_files(&config.input_path, config)?,
+    };
+    let (mut images, unsupported): (Vec<PathBuf>, Vec<PathBuf>) =
+        files.into_iter().partition(|path| is_image_file(path, config));
+
+    // In-place runs leave outputs next to their originals, which must not be resized again
+    let mut earlier_outputs = 0;
+    if config.strip_resized_suffix && config.input_path.is_dir() {
+        let before = images.len();
+        images.retain(|path| !has_resized_suffix(path));
+        earlier_outputs = before - images.len();
+    }
+
+    if images.is_empty() {
+        return finish_without_images("❌ No image files found!", &unsupported, config);
+    }
+
+    if !config.quiet {
+        println!("📸 Found {} image(s) to process", images.len());
+        if earlier_outputs > 0 {
+            println!("⏭️  Skipping {} earlier output(s) ending in {}", earlier_outputs, RESIZED_SUFFIX);
+        }
+    }
+
+    // Skip inputs finished by an earlier, interrupted run
+    if let Some(state_path) = &config.resume {
+        let completed = load_resume_state(state_path)?;
+        let before = images.len();
+        images.retain(|path| !completed.contains(&resume_key(path)));
+        if !config.quiet && images.len() < before {
+            println!("⏭️  Skipping {} image(s) already completed in {}", before - images.len(), state_path.display());
+        }
+    }
+
+    // Only the first frame would survive, so animations are left out rather than flattened
+    if config.skip_animated {
+        let (animated, still): (Vec<PathBuf>, Vec<PathBuf>) = images.into_iter().partition(|path| is_animated(path));
+        images = still;
+        if !config.quiet && !animated.is_empty() {
+            println!("⏭️  Skipping {} animated image(s) (--skip-animated)", animated.len());
+            if config.verbose {
+                for path in &animated {
+                    println!("  {}", path.display());
+                }
+            }
+        }
+    }
+
+    if let Some((min_width, min_height)) = config.min_dimensions {
+        // Only the header is read; files whose size can't be read are left for processing to report
+        let (small, large): (Vec<PathBuf>, Vec<PathBuf>) = images.into_iter().partition(|path| {
+            !(config.raw_input.is_some() && is_raw_file(path))
+                && header_dimensions(path).is_some_and(|(width, height)| width < min_width || height < min_height)
+        });
+        images = large;
+        if !config.quiet && !small.is_empty() {
+            println!("⏭️  Skipping {} image(s) smaller than {}x{} (--min-dimensions)", small.len(), min_width, min_height);
+            if config.verbose {
+                for path in &small {
+                    println!("  {}", path.display());
+                }
+            }
+        }
+    }
+
+    if images.is_empty() {
+        return finish_without_images("✅ Nothing left to process", &unsupported, config);
+    }
+
+    if config.shuffle {
+        use rand::seq::SliceRandom;
+        use rand::SeedableRng;
+
+        // Always report the seed so a random sample can be repeated with --seed
+        let seed = config.seed.unwrap_or_else(rand::random);
+        images.shuffle(&mut rand::rngs::StdRng::seed_from_u64(seed));
+        if !config.quiet {
+            println!("🔀 Shuffled with seed {}", seed);
+        }
+    }
+
+    if let Some(limit) = config.limit {
+        if limit < images.len() {
+            images.truncate(limit);
+            if !config.quiet {
+                println!("✂️  Limited to the first {} image(s)", limit);
+            }
+        }
+    }
+
+    if config.plan {
+        return plan_images(&images, config);
+    }
+
+    if config.collect_stats {
+        print_input_stats(&images, config);
+        return Ok(());
+    }
+
+    // A mistyped path such as / would otherwise start resizing a whole disk. Checked before anything
+    // is written, including the --copy-unsupported mirror and the --resume state file
+    if let Some(max_files) = config.max_files.filter(|&max_files| images.len() > max_files) {
+        if !config.over_max_files {
+            return Err(format!(
+                "Found {} images in {}, more than --max-files {}; raise --max-files, narrow the input or pass --no-limit",
+                images.len(),
+                config.input_path.display(),
+                max_files
+            )
+            .into());
+        }
+        if !config.quiet {
+            println!("⚠️  Found {} images, more than --max-files {}; continuing because of --yes", images.len(), max_files);
+        }
+    }
+
+    // Copied before the image pass so the mirror is complete even if it is interrupted
+    let mut copied = if config.copy_unsupported {
+        copy_unsupported(&unsupported, config)
+    } else {
+        Vec::new()
+    };
+
+    let resume_log = match &config.resume {
+        Some(state_path) => Some(Mutex::new(fs::OpenOptions::new().create(true).append(true).open(state_path)?)),
+        None => None,
+    };
+
+    if config.overwrite_input && !config.quiet {
+        println!("⚠️  --overwrite-input: replacing {} original file(s) in place, this cannot be undone", images.len());
+    }
+
+    if !config.no_write {
+        ensure_output_dirs(&images, config)?;
+    }
+    
+    let pb = if config.quiet {
+        ProgressBar::hidden()
+    } else {
+        ProgressBar::new(images.len() as u64)
+    };
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta}) {msg}")?
+            .progress_chars("#>-"),
+    );
+
+    let total = images.len();
+    if config.progress_json {
+        emit_progress(&ProgressEvent::Start { total });
+    }
+    
+    let results: Vec<ProcessResult> = if config.parallel {
+        // Workers share no lock per file: rayon gathers each thread's results and merges them in
+        // input order, and the progress bar position is atomic
+        let completed = AtomicUsize::new(0);
+        pb.set_message("Processing in parallel");
+        let results = images
+            .par_iter()
+            .enumerate()
+            .map(|(index, image_path)| {
+                let mut result = match config.verbose {
+                    true => process_buffered(image_path, index + 1, config),
+                    false => process_single_image_with_result(image_path, index + 1, config),
+                };
+                record_completed(resume_log.as_ref(), &mut result);
+                pb.inc(1);
+                if config.progress_json {
+                    emit_file_progress(&result, completed.fetch_add(1, Ordering::Relaxed) + 1, total);
+                }
+                result
+            })
+            .collect();
+        
+        pb.finish_with_message("✨ Processing complete!");
+        results
+    } else {
+        let mut results = Vec::with_capacity(total);
+        for (index, image_path) in images.iter().enumerate() {
+            if let Some(file_name) = image_path.file_name() {
+                pb.set_message(format!("Processing: {}", file_name.to_string_lossy()));
+            }
+            
+            let mut result = process_single_image_with_result(image_path, index + 1, config);
+            record_completed(resume_log.as_ref(), &mut result);
+            if config.progress_json {
+                emit_file_progress(&result, index + 1, total);
+            }
+            results.push(result);
+            pb.inc(1);
+        }
+        pb.finish_with_message("✨ Processing complete!");
+        results
+    };
+
+    // Files named like images that turn out not to decode are mirrored like any other file
+    let results = if config.copy_unsupported {
+        let (undecodable, results): (Vec<ProcessResult>, Vec<ProcessResult>) =
+            results.into_iter().partition(|result| result.undecodable);
+        let paths: Vec<PathBuf> = undecodable.into_iter().map(|result| result.input_path).collect();
+        copied.extend(copy_unsupported(&paths, config));
+        results
+    } else {
+        results
+    };
+
+    let successful = results.iter().filter(|r| r.success).count();
+    let failed = results.len() - successful;
+    
+    let total_original: u64 = results.iter().map(|r| r.original_size).sum();
+    let total_final: u64 = results.iter().filter(|r| r.success).map(|r| r.final_size).sum();
+    let total_saved = total_original.saturating_sub(total_final);
+    let kept = results.iter().filter(|r| r.kept_original).count();
+    let passed_through = results.iter().filter(|r| r.passed_through).count();
+    let rotated = results.iter().filter(|r| r.rotated).count();
+    let below_quality = results.iter().filter(|r| r.below_quality).count();
+    let converted = results.iter().filter(|r| r.converted_from.is_some()).count();
+    let metrics: Vec<QualityMetrics> = results.iter().filter_map(|r| r.metrics).collect();
+    let average = |metric: fn(&QualityMetrics) -> f64| {
+        (!metrics.is_empty()).then(|| metrics.iter().map(metric).sum::<f64>() / metrics.len() as f64)
+    };
+
+    let summary = BatchSummary {
+        total: results.len(),
+        successful,
+        failed,
+        kept_original: kept,
+        original_bytes: total_original,
+        final_bytes: total_final,
+        saved_bytes: total_saved,
+        saved_percent: if total_original > 0 {
+            (total_saved as f64 / total_original as f64) * 100.0
+        } else {
+            0.0
+        },
+        average_ssim: average(|m| m.ssim),
+        average_psnr: average(|m| m.psnr),
+    };
+
+    if config.progress_json {
+        emit_progress(&ProgressEvent::Done(&summary));
+    }
+
+    if let Some(report_path) = &config.report {
+        write_report(report_path, &summary, &results, config)?;
+    }
+
+    if let Some(sheet_path) = &config.contact_sheet {
+        write_contact_sheet(sheet_path, &results, config.columns)?;
+    }
+
+    // Only written when something failed, so an existing list from an earlier run is left alone
+    let failures_list = match &config.failures_list {
+        Some(list_path) if failed > 0 => {
+            write_failures_list(list_path, &results)?;
+            Some(list_path)
+        }
+        _ => None,
+    };
+
+    let error_log = match &config.error_log {
+        Some(log_path) if failed > 0 => {
+            write_error_log(log_path, &results)?;
+            Some(log_path)
+        }
+        _ => None,
+    };
+
+    if config.summary_json {
+        println!("{}", serde_json::to_string(&summary)?);
+        return Ok(());
+    }
+
+    if config.quiet {
+        return Ok(());
+    }
+
+    // Print summary
+    println!("\n📊 Processing Summary:");
+    println!("{}", "─".repeat(60));
+    
+    println!("✅ Successful: {}", successful);
+    println!("❌ Failed: {}", failed);
+    if kept > 0 {
+        println!("📦 Kept original: {} (re-encoding would have been larger)", kept);
+    }
+    if passed_through > 0 {
+        println!("⏩ Passed through unchanged: {} (at or under {} KB)", passed_through, config.recompress_above_kb.unwrap_or_default());
+    }
+    if let Some(orientation) = config.normalize_orientation {
+        println!("🔄 Rotated to {}: {}", if orientation == Orientation::Portrait { "portrait" } else { "landscape" }, rotated);
+    }
+    if converted > 0 {
+        println!("🔁 Converted {} file(s) to {} to reach the size target", converted,
+            config.fallback_format.map(format_name).unwrap_or_default());
+    }
+    if below_quality > 0 {
+        println!("⚠️  {} file(s) compressed below quality {}", below_quality, config.warn_below_quality);
+    }
+	if successful > 0 {
+		println!("💾 Total saved: {} KB ({:.1}% reduction)", 
+			total_saved / 1024, 
+			(total_saved as f64 / total_original as f64) * 100.0
+		);
+		let saved_per_file: u64 = results
+			.iter()
+			.filter(|r| r.success)
+			.map(|r| r.original_size.saturating_sub(r.final_size))
+			.sum::<u64>() / successful as u64;
+		println!("📥 Input scanned: {} KB across {} file(s)", total_original / 1024, results.len());
+		println!("📤 Output written: {} KB across {} file(s)", total_final / 1024, successful);
+		println!("📉 Average saved per file: {} KB", saved_per_file / 1024);
+		if let (Some(ssim), Some(psnr)) = (summary.average_ssim, summary.average_psnr) {
+			println!("📐 Average quality: SSIM {:.4}, PSNR {:.2} dB", ssim, psnr);
+		}
+	} else if failed > 0 {
+		println!("❌ Couldn't reach target file size, specify -c to auto scale image");
+	}
+
+    print_format_breakdown(&results);
+    if config.group_by_directory {
+        print_directory_breakdown(&results, config);
+    }
+    if let Some(threshold) = config.summary_threshold {
+        print_worst_files(&results, threshold);
+    }
+
+    let warnings: Vec<&String> = results.iter().flat_map(|r| &r.warnings).collect();
+    if !warnings.is_empty() {
+        println!("\n⚠️  Warnings:");
+        for warning in warnings {
+            println!("  {}", warning);
+        }
+    }
+
+    if config.copy_unsupported {
+        print_copied(&copied);
+    }
+
+    if config.no_write {
+        println!("\n🧪 --no-write: encoded outputs were discarded, nothing was saved");
+    }
+
+    if let Some(list_path) = failures_list {
+        println!("\n📝 Failed paths written to {}; retry them with --stdin-list < {}", list_path.display(), list_path.display());
+    }
+
+    if let Some(log_path) = error_log {
+        println!("\n📝 Full error details written to {}", log_path.display());
+    }
+
+    if config.profile {
+        print_stage_timings(&results);
+    }
+    
+    if config.verbose {
+        println!("\n📋 Detailed Results:");
+        for result in results.iter() {
+            if result.success {
+                let (width, height) = result.dimensions.unwrap_or_default();
+                println!("  ✓ {} → {} ({} KB → {} KB, {}x{}) {}",
+                    result.input_path.file_name().unwrap().to_string_lossy(),
+                    result.output_path.file_name().unwrap().to_string_lossy(),
+                    result.original_size / 1024,
+                    result.final_size / 1024,
+                    width,
+                    height,
+                    result.message
+                );
+            } else {
+                println!("  ✗ {} - {}",
+                    result.input_path.file_name().unwrap().to_string_lossy(),
                     result.message
                 );
             }
+            if !result.log.is_empty() {
+                for line in &result.log {
+                    println!("  {}", line);
+                }
+                let t = result.timings;
+                let total = t.decode + t.color + t.resize + t.compress + t.encode + t.write;
+                println!("    ⏱️  {:.3}s", total.as_secs_f64());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+thread_local! {
+    // Some while a --parallel worker processes an image, so its verbose lines can be printed together
+    static VERBOSE_LOG: RefCell<Option<Vec<String>>> = const { RefCell::new(None) };
+}
+
+// Per-image verbose output; printed straight away unless the current worker is buffering it
+fn log_verbose(line: String) {
+    VERBOSE_LOG.with(|log| match log.borrow_mut().as_mut() {
+        Some(lines) => lines.push(line),
+        None => println!("{}", line),
+    });
+}
+
+// Images don't share a worker mid-way, as nothing inside process_single_image_with_result uses rayon
+fn process_buffered(image_path: &Path, index: usize, config: &Config) -> ProcessResult {
+    VERBOSE_LOG.with(|log| *log.borrow_mut() = Some(Vec::new()));
+    let result = process_single_image_with_result(image_path, index, config);
+    let log = VERBOSE_LOG.with(|log| log.borrow_mut().take()).unwrap_or_default();
+    ProcessResult { log, ..result }
+}
+
+// eprintln! locks stderr for the whole line, so events from parallel workers never interleave
+fn emit_progress(event: &ProgressEvent) {
+    if let Ok(line) = serde_json::to_string(event) {
+        eprintln!("{}", line);
+    }
+}
+
+fn emit_file_progress(result: &ProcessResult, completed: usize, total: usize) {
+    emit_progress(&ProgressEvent::File {
+        completed,
+        total,
+        input: &result.input_path,
+        output: result.success.then_some(result.output_path.as_path()),
+        success: result.success,
+        original_bytes: result.original_size,
+        final_bytes: result.final_size,
+        message: &result.message,
+    });
+}
+
+fn write_report(
+    path: &Path,
+    summary: &BatchSummary,
+    results: &[ProcessResult],
+    config: &Config,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let hasher = config.hash_algorithm.map(content_hasher);
+
+    let files = results
+        .iter()
+        .map(|result| {
+            let hash = match &hasher {
+                Some(hasher) if result.success => Some(hasher.hash(&fs::read(&result.output_path)?)),
+                _ => None,
+            };
+            Ok(FileReport {
+                input: result.input_path.clone(),
+                output: result.success.then(|| result.output_path.clone()),
+                success: result.success,
+                original_bytes: result.original_size,
+                final_bytes: result.final_size,
+                width: result.dimensions.map(|(width, _)| width),
+                height: result.dimensions.map(|(_, height)| height),
+                ssim: result.metrics.map(|metrics| metrics.ssim),
+                psnr: result.metrics.map(|metrics| metrics.psnr),
+                message: result.message.clone(),
+                hash,
+                post_exit_code: result.post_exit_code,
+            })
+        })
+        .collect::<Result<Vec<_>, std::io::Error>>()?;
+
+    let report = Report {
+        schema_version: REPORT_SCHEMA_VERSION,
+        summary,
+        hash_algorithm: hasher.as_ref().map(|hasher| hasher.name()),
+        files,
+    };
+    let json = if config.pretty {
+        serde_json::to_string_pretty(&report)?
+    } else {
+        serde_json::to_string(&report)?
+    };
+    fs::write(path, json)?;
+    Ok(())
+}
+
+// One input path per line, in the form --stdin-list reads back
+fn write_failures_list(path: &Path, results: &[ProcessResult]) -> Result<(), Box<dyn std::error::Error>> {
+    let list: String = results
+        .iter()
+        .filter(|result| !result.success)
+        .map(|result| format!("{}\n", result.input_path.display()))
+        .collect();
+    fs::write(path, list)?;
+    Ok(())
+}
+
+// Each failed path followed by its message and indented error detail, blank line between files
+fn write_error_log(path: &Path, results: &[ProcessResult]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut log = String::new();
+    for result in results.iter().filter(|result| !result.success) {
+        log.push_str(&format!("{}\n    {}\n", result.input_path.display(), result.message));
+        for line in &result.error_detail {
+            log.push_str(&format!("    {}\n", line));
+        }
+        log.push('\n');
+    }
+    fs::write(path, log)?;
+    Ok(())
+}
+
+fn write_contact_sheet(path: &Path, results: &[ProcessResult], columns: u32) -> Result<(), Box<dyn std::error::Error>> {
+    // Parallel runs finish in any order, so lay the sheet out by input path
+    let mut thumbnails: Vec<(&Path, &image::RgbaImage)> = results
+        .iter()
+        .filter_map(|r| Some((r.input_path.as_path(), r.thumbnail.as_ref()?)))
+        .collect();
+    if thumbnails.is_empty() {
+        return Ok(());
+    }
+    thumbnails.sort_by_key(|(input, _)| *input);
+
+    let count = thumbnails.len() as u32;
+    let columns = columns.min(count);
+    let rows = count.div_ceil(columns);
+    let cell_width = CONTACT_SHEET_CELL + CONTACT_SHEET_PADDING;
+    let cell_height = CONTACT_SHEET_CELL + CONTACT_SHEET_CAPTION + CONTACT_SHEET_PADDING;
+
+    let white = image::Rgba([255, 255, 255, 255]);
+    let mut sheet = image::RgbaImage::from_pixel(
+        columns * cell_width + CONTACT_SHEET_PADDING,
+        rows * cell_height + CONTACT_SHEET_PADDING,
+        white,
+    );
+
+    for (i, (input, thumbnail)) in thumbnails.into_iter().enumerate() {
+        let x = CONTACT_SHEET_PADDING + (i as u32 % columns) * cell_width;
+        let y = CONTACT_SHEET_PADDING + (i as u32 / columns) * cell_height;
+
+        // Center each thumbnail in its cell, with transparency blended onto the white sheet
+        let offset_x = x + (CONTACT_SHEET_CELL - thumbnail.width()) / 2;
+        let offset_y = y + (CONTACT_SHEET_CELL - thumbnail.height()) / 2;
+        image::imageops::overlay(&mut sheet, thumbnail, offset_x as i64, offset_y as i64);
+
+        let name = input.file_name().unwrap_or_default().to_string_lossy();
+        draw_caption(&mut sheet, &name, x, y + CONTACT_SHEET_CELL + 4);
+    }
+
+    // JPEG and other formats without alpha need RGB pixels
+    DynamicImage::ImageRgba8(sheet).to_rgb8().save(path)?;
+    Ok(())
+}
+
+// Draw text with the built-in 8x8 font, cut short with ".." when it is wider than a cell
+fn draw_caption(sheet: &mut image::RgbaImage, text: &str, x: u32, y: u32) {
+    let max_chars = (CONTACT_SHEET_CELL / 8) as usize;
+    let mut chars: Vec<char> = text.chars().collect();
+    if chars.len() > max_chars {
+        chars.truncate(max_chars - 2);
+        chars.extend(['.', '.']);
+    }
+
+    let black = image::Rgba([0, 0, 0, 255]);
+    for (i, c) in chars.into_iter().enumerate() {
+        let glyph = font8x8::legacy::BASIC_LEGACY[if c.is_ascii() { c as usize } else { '?' as usize }];
+        for (row, bits) in glyph.iter().enumerate() {
+            for col in 0..8 {
+                if bits & (1 << col) != 0 {
+                    sheet.put_pixel(x + i as u32 * 8 + col, y + row as u32, black);
+                }
+            }
+        }
+    }
+}
+
+// Hashes in the report are lowercase hex so they can be compared with external tools
+trait ContentHasher {
+    fn name(&self) -> &'static str;
+    fn hash(&self, data: &[u8]) -> String;
+}
+
+struct Blake3Hasher;
+struct Sha256Hasher;
+struct XxHasher;
+
+impl ContentHasher for Blake3Hasher {
+    fn name(&self) -> &'static str {
+        "blake3"
+    }
+
+    fn hash(&self, data: &[u8]) -> String {
+        blake3::hash(data).to_hex().to_string()
+    }
+}
+
+impl ContentHasher for Sha256Hasher {
+    fn name(&self) -> &'static str {
+        "sha256"
+    }
+
+    fn hash(&self, data: &[u8]) -> String {
+        use sha2::Digest;
+        to_hex(&sha2::Sha256::digest(data))
+    }
+}
+
+impl ContentHasher for XxHasher {
+    fn name(&self) -> &'static str {
+        "xxh3-64"
+    }
+
+    fn hash(&self, data: &[u8]) -> String {
+        format!("{:016x}", xxhash_rust::xxh3::xxh3_64(data))
+    }
+}
+
+fn content_hasher(algorithm: HashAlgorithm) -> Box<dyn ContentHasher> {
+    match algorithm {
+        HashAlgorithm::Blake3 => Box::new(Blake3Hasher),
+        HashAlgorithm::Sha256 => Box::new(Sha256Hasher),
+        HashAlgorithm::XxHash => Box::new(XxHasher),
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn resume_key(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+fn load_resume_state(path: &Path) -> Result<BTreeSet<PathBuf>, Box<dyn std::error::Error>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(BTreeSet::new()),
+        Err(e) => return Err(format!("Failed to read resume state {}: {}", path.display(), e).into()),
+    };
+
+    // A line cut short by an interruption is ignored, so that file is simply redone
+    Ok(contents
+        .lines()
+        .filter_map(|line| serde_json::from_str::<ResumeEntry>(line).ok())
+        .map(|entry| entry.input)
+        .collect())
+}
+
+// Append one line per finished image; the lock keeps lines from parallel workers whole
+fn record_completed(log: Option<&Mutex<fs::File>>, result: &mut ProcessResult) {
+    use std::io::Write;
+
+    let Some(log) = log else { return };
+    if !result.success {
+        return;
+    }
+
+    let entry = ResumeEntry { input: resume_key(&result.input_path) };
+    let written = serde_json::to_string(&entry)
+        .map_err(|e| e.to_string())
+        .and_then(|line| writeln!(log.lock().unwrap(), "{}", line).map_err(|e| e.to_string()));
+    if let Err(e) = written {
+        result.warnings.push(format!("{}: could not update resume state: {}", result.input_path.display(), e));
+    }
+}
+
+// Header-only reads, so even a large tree is summarized without decoding any pixels
+fn print_input_stats(images: &[PathBuf], config: &Config) {
+    let read = |path: &PathBuf| {
+        let size = fs::metadata(path).map(|m| m.len()).ok();
+        let dimensions = if config.raw_input.is_some() && is_raw_file(path) {
+            config.raw_input
+        } else {
+            header_dimensions(path)
+        };
+        (size, dimensions)
+    };
+    let stats: Vec<_> = if config.parallel {
+        images.par_iter().map(read).collect()
+    } else {
+        images.iter().map(read).collect()
+    };
+
+    let mut sizes: Vec<u64> = stats.iter().filter_map(|(size, _)| *size).collect();
+    let mut edges: Vec<u32> = stats.iter().filter_map(|(_, dims)| dims.map(|(w, h)| w.max(h))).collect();
+    let mut pixels: Vec<u64> = stats
+        .iter()
+        .filter_map(|(_, dims)| dims.map(|(w, h)| w as u64 * h as u64))
+        .collect();
+    sizes.sort_unstable();
+    edges.sort_unstable();
+    pixels.sort_unstable();
+
+    println!("\n📊 Input Statistics:");
+    println!("{}", "─".repeat(60));
+    if !sizes.is_empty() {
+        println!("📦 File size: min {} KB, median {} KB, max {} KB, total {} KB",
+            sizes[0] / 1024,
+            sizes[sizes.len() / 2] / 1024,
+            sizes[sizes.len() - 1] / 1024,
+            sizes.iter().sum::<u64>() / 1024
+        );
+    }
+    if !pixels.is_empty() {
+        println!("📐 Resolution: min {:.2} MP, median {:.2} MP, max {:.2} MP",
+            pixels[0] as f64 / 1_000_000.0,
+            pixels[pixels.len() / 2] as f64 / 1_000_000.0,
+            pixels[pixels.len() - 1] as f64 / 1_000_000.0
+        );
+    }
+
+    println!("\nLonger side:");
+    let edge_labels = histogram_labels(STATS_EDGE_BINS, "px");
+    print_histogram(&edge_labels, &bin_counts(&edges, STATS_EDGE_BINS));
+
+    println!("\nFile size:");
+    let size_kb: Vec<u64> = sizes.iter().map(|size| size / 1024).collect();
+    let size_labels = histogram_labels(STATS_SIZE_BINS_KB, "KB");
+    print_histogram(&size_labels, &bin_counts(&size_kb, STATS_SIZE_BINS_KB));
+
+    let unreadable = stats.iter().filter(|(_, dims)| dims.is_none()).count();
+    if unreadable > 0 {
+        println!("\n⚠️  {} image(s) had no readable dimensions and are left out of the resolution histogram", unreadable);
+    }
+}
+
+fn bin_counts<T: PartialOrd + Copy>(values: &[T], bounds: &[T]) -> Vec<usize> {
+    let mut counts = vec![0; bounds.len() + 1];
+    for &value in values {
+        counts[bounds.iter().take_while(|&&bound| value >= bound).count()] += 1;
+    }
+    counts
+}
+
+fn histogram_labels<T: std::fmt::Display>(bounds: &[T], unit: &str) -> Vec<String> {
+    let mut labels = Vec::with_capacity(bounds.len() + 1);
+    labels.push(format!("< {} {}", bounds[0], unit));
+    for pair in bounds.windows(2) {
+        labels.push(format!("{}–{} {}", pair[0], pair[1], unit));
+    }
+    labels.push(format!("≥ {} {}", bounds[bounds.len() - 1], unit));
+    labels
+}
+
+fn print_histogram(labels: &[String], counts: &[usize]) {
+    let total: usize = counts.iter().sum();
+    let largest = counts.iter().copied().max().unwrap_or(0).max(1);
+    for (label, &count) in labels.iter().zip(counts) {
+        let bar = "█".repeat((count * STATS_BAR_WIDTH).div_ceil(largest));
+        let percent = if total == 0 { 0.0 } else { count as f64 / total as f64 * 100.0 };
+        println!("  {:<16} {:>6} {:>5.1}%  {}", label, count, percent, bar);
+    }
+}
+
+fn plan_images(images: &[PathBuf], config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let mut sized: Vec<(PathBuf, u64)> = images
+        .iter()
+        .map(|p| (p.clone(), fs::metadata(p).map(|m| m.len()).unwrap_or(0)))
+        .collect();
+    let total_original: u64 = sized.iter().map(|(_, size)| size).sum();
+
+    // Pick evenly spaced files by size so small and large images are both represented
+    sized.sort_by_key(|(_, size)| *size);
+    let sample: Vec<(PathBuf, u64)> = if sized.len() <= PLAN_SAMPLE_SIZE {
+        sized.clone()
+    } else {
+        (0..PLAN_SAMPLE_SIZE)
+            .map(|i| sized[i * (sized.len() - 1) / (PLAN_SAMPLE_SIZE - 1)].clone())
+            .collect()
+    };
+
+    println!("🔎 Planning with {} of {} image(s)...", sample.len(), images.len());
+
+    let run = |(path, size): &(PathBuf, u64)| {
+        let estimate = image_config(path, config).and_then(|merged| {
+            let config = merged.as_ref().unwrap_or(config);
+            if passes_through(path, *size, config) {
+                return Ok(*size);
+            }
+            render_image(path, config, &mut StageTimings::default()).map(|r| r.data.len() as u64)
+        });
+        (*size, estimate.map_err(|e| format!("{} - {}", path.display(), e)))
+    };
+    let outcomes: Vec<(u64, Result<u64, String>)> = if config.parallel {
+        sample.par_iter().map(run).collect()
+    } else {
+        sample.iter().map(run).collect()
+    };
+
+    let sample_total: u64 = outcomes.iter().map(|(size, _)| size).sum();
+    let mut sample_original = 0;
+    let mut sample_final = 0;
+    let mut sample_failed = 0;
+    for (size, result) in &outcomes {
+        match result {
+            Ok(final_size) => {
+                sample_original += size;
+                sample_final += final_size;
+            }
+            Err(e) => {
+                sample_failed += 1;
+                if config.verbose {
+                    println!("  ✗ {}", e);
+                }
+            }
+        }
+    }
+
+    println!("\n📐 Plan Estimate:");
+    println!("{}", "─".repeat(60));
+    println!("📥 Total input: {} KB", total_original / 1024);
+
+    if sample_original == 0 {
+        println!("❌ None of the sampled images could be processed with these settings");
+        return Ok(());
+    }
+
+    // Failed samples produce no output, so only extrapolate over the share expected to succeed
+    let ratio = sample_final as f64 / sample_original as f64;
+    let succeeding_input = total_original as f64 * (sample_original as f64 / sample_total as f64);
+    let estimated = (succeeding_input * ratio) as u64;
+    let change = if ratio <= 1.0 {
+        format!("{:.1}% reduction", (1.0 - ratio) * 100.0)
+    } else {
+        format!("{:.1}% increase", (ratio - 1.0) * 100.0)
+    };
+    println!("📤 Estimated output: ~{} KB ({})", estimated / 1024, change);
+    if sample_failed > 0 {
+        println!("⚠️  {} of {} sampled image(s) failed; expect a similar share to fail in the full run",
+            sample_failed,
+            sample.len()
+        );
+    }
+    if sample.len() < images.len() {
+        println!("ℹ️  Extrapolated from a sample of {} image(s); actual results may vary", sample.len());
+    }
+
+    Ok(())
+}
+
+fn print_directory_breakdown(results: &[ProcessResult], config: &Config) {
+    // (successful, failed, original bytes of successful, final bytes)
+    let mut by_directory: BTreeMap<String, (usize, usize, u64, u64)> = BTreeMap::new();
+    for result in results {
+        let parent = result.input_path.parent().unwrap_or(Path::new(""));
+        // Relative to the input so deep trees stay readable; the input folder itself is "."
+        let name = match parent.strip_prefix(&config.input_path) {
+            Ok(relative) if relative.as_os_str().is_empty() => ".".to_string(),
+            Ok(relative) => relative.display().to_string(),
+            Err(_) => parent.display().to_string(),
+        };
+        let entry = by_directory.entry(name).or_default();
+        if result.success {
+            entry.0 += 1;
+            entry.2 += result.original_size;
+            entry.3 += result.final_size;
+        } else {
+            entry.1 += 1;
+        }
+    }
+
+    let mut rows: Vec<_> = by_directory.into_iter().collect();
+    // Most savings first, ties in path order
+    rows.sort_by_key(|(_, (_, _, original, final_size))| std::cmp::Reverse(original.saturating_sub(*final_size)));
+    let width = rows.iter().map(|(name, _)| name.chars().count()).max().unwrap_or(0);
+
+    println!("\n📂 By Directory:");
+    for (name, (successful, failed, original, final_size)) in rows {
+        let saved = original.saturating_sub(final_size);
+        let percent = if original > 0 { saved as f64 / original as f64 * 100.0 } else { 0.0 };
+        println!("  {:<width$}  ✅ {}  ❌ {}  💾 {} KB ({:.1}% reduction)",
+            name, successful, failed, saved / 1024, percent
+        );
+    }
+}
+
+// Files that saved less than the threshold, smallest reduction first; passed-through files were
+// left alone on purpose and aren't listed
+fn print_worst_files(results: &[ProcessResult], threshold: f64) {
+    let mut worst: Vec<(&ProcessResult, f64)> = results
+        .iter()
+        .filter(|r| r.success && !r.passed_through && r.original_size > 0)
+        .map(|r| (r, (r.original_size as f64 - r.final_size as f64) / r.original_size as f64 * 100.0))
+        .filter(|(_, percent)| *percent < threshold)
+        .collect();
+    if worst.is_empty() {
+        return;
+    }
+    worst.sort_by(|a, b| a.1.total_cmp(&b.1));
+
+    println!("\n🐢 Saved less than {}%:", threshold);
+    for (result, percent) in worst.iter().take(WORST_FILES_LISTED) {
+        println!("  {} ({} KB → {} KB, {:.1}% reduction)",
+            result.input_path.display(),
+            result.original_size / 1024,
+            result.final_size / 1024,
+            percent
+        );
+    }
+    if worst.len() > WORST_FILES_LISTED {
+        println!("  … and {} more", worst.len() - WORST_FILES_LISTED);
+    }
+}
+
+fn print_format_breakdown(results: &[ProcessResult]) {
+    // (successful, failed, original bytes of successful, final bytes)
+    let mut by_format: BTreeMap<&str, (usize, usize, u64, u64)> = BTreeMap::new();
+    for result in results {
+        let entry = by_format
+            .entry(result.format.map(format_name).unwrap_or("Other"))
+            .or_default();
+        if result.success {
+            entry.0 += 1;
+            entry.2 += result.original_size;
+            entry.3 += result.final_size;
+        } else {
+            entry.1 += 1;
+        }
+    }
+
+    // Only worth breaking down for mixed trees
+    if by_format.len() < 2 {
+        return;
+    }
+
+    println!("\n🗂️  By Format:");
+    for (name, (successful, failed, original, final_size)) in by_format {
+        let saved = original.saturating_sub(final_size);
+        let percent = if original > 0 { saved as f64 / original as f64 * 100.0 } else { 0.0 };
+        println!("  {:<5} ✅ {}  ❌ {}  💾 {} KB ({:.1}% reduction)",
+            name, successful, failed, saved / 1024, percent
+        );
+    }
+}
+
+fn print_stage_timings(results: &[ProcessResult]) {
+    let mut total = StageTimings::default();
+    for timings in results.iter().map(|r| r.timings) {
+        total.decode += timings.decode;
+        total.color += timings.color;
+        total.resize += timings.resize;
+        total.compress += timings.compress;
+        total.encode += timings.encode;
+        total.write += timings.write;
+    }
+
+    let stages = [
+        ("decode", total.decode),
+        ("color", total.color),
+        ("resize", total.resize),
+        ("compress search", total.compress),
+        ("encode", total.encode),
+        ("write", total.write),
+    ];
+    let sum: Duration = stages.iter().map(|(_, d)| *d).sum();
+
+    println!("\n⏱️  Stage Timings (summed across images):");
+    for (name, duration) in stages {
+        let percent = if sum.is_zero() { 0.0 } else { duration.as_secs_f64() / sum.as_secs_f64() * 100.0 };
+        println!("  {:<16} {:>9.3}s ({:.1}%)", name, duration.as_secs_f64(), percent);
+    }
+}
+
+fn watch_images(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    use notify::event::{EventKind, ModifyKind};
+    use notify::{RecursiveMode, Watcher};
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    watcher.watch(&config.input_path, RecursiveMode::Recursive)?;
+
+    println!("\n👀 Watching {} for new images (Ctrl-C to stop)", config.input_path.display());
+
+    let mut index = 0;
+    for event in rx {
+        let event = match event {
+            Ok(event) => event,
+            Err(e) => {
+                println!("⚠️  Watch error: {}", e);
+                continue;
+            }
+        };
+
+        // Files copied or moved into the folder show up as creates or renames
+        if !matches!(event.kind, EventKind::Create(_) | EventKind::Modify(ModifyKind::Name(_))) {
+            continue;
+        }
+
+        for path in event.paths {
+            if !is_image_file(&path, config) || is_output_path(&path, config) || !wait_until_settled(&path) {
+                continue;
+            }
+
+            // New subdirectories can appear while watching
+            if let Err(e) = ensure_output_dirs(std::slice::from_ref(&path), config) {
+                println!("✗ {} - {}", path.display(), e);
+                continue;
+            }
+
+            index += 1;
+            let result = process_single_image_with_result(&path, index, config);
+            if result.success {
+                println!("✓ Processed: {} ({} KB → {} KB)",
+                    path.display(),
+                    result.original_size / 1024,
+                    result.final_size / 1024
+                );
+            } else {
+                println!("✗ {} - {}", path.display(), result.message);
+            }
+            for warning in &result.warnings {
+                println!("  ⚠️  {}", warning);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn wait_until_settled(path: &Path) -> bool {
+    let mut last_size = None;
+    loop {
+        std::thread::sleep(WATCH_SETTLE_TIME);
+        match fs::metadata(path) {
+            Ok(metadata) if metadata.is_file() => {
+                let size = metadata.len();
+                if size > 0 && last_size == Some(size) {
+                    return true;
+                }
+                last_size = Some(size);
+            }
+            // Removed or replaced by a directory before it settled
+            _ => return false,
+        }
+    }
+}
+
+fn is_output_path(path: &Path, config: &Config) -> bool {
+    match &config.output_dir {
+        Some(dir) => match (fs::canonicalize(dir), path.parent().map(fs::canonicalize)) {
+            (Ok(dir), Some(Ok(parent))) => parent.starts_with(dir),
+            _ => false,
+        },
+        None => path.parent().and_then(|p| p.file_name()) == Some("resized".as_ref()),
+    }
+}
+
+fn process_single_image_with_result(input_path: &Path, index: usize, config: &Config) -> ProcessResult {
+    let mut timings = StageTimings::default();
+    // Read before anything below opens the file, which updates its access time for --copy-timestamps
+    let source_metadata = match fs::metadata(input_path) {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            return ProcessResult {
+                input_path: input_path.to_path_buf(),
+                output_path: PathBuf::new(),
+                original_size: 0,
+                final_size: 0,
+                success: false,
+                message: format!("Failed to read file metadata: {}", e),
+                error_detail: error_detail(&e),
+                format: input_format(input_path),
+                ..Default::default()
+            };
+        }
+    };
+    let original_size = source_metadata.len();
+    let format = input_format(input_path);
+    let mut warnings = Vec::new();
+    if let (Some(detected), Ok(named)) = (detect_format(input_path), get_image_format(input_path)) {
+        if detected != named {
+            warnings.push(format!("{} contains {} data but is named as {}; decoding as {}",
+                input_path.display(), format_name(detected), format_name(named), format_name(detected)));
+        }
+    }
+    // Resizing always works from the decoded pixels; the EXIF values are only reported
+    if config.check_exif_dimensions {
+        if let (Some(exif), Ok(actual)) = (exif_dimensions(input_path), image::image_dimensions(input_path)) {
+            if exif != actual {
+                warnings.push(format!("{} has EXIF dimensions {}x{} but decodes as {}x{}; using the decoded size",
+                    input_path.display(), exif.0, exif.1, actual.0, actual.1));
+            }
+        }
+    }
+
+    // Merged once, so every step from the pass-through check to the result message sees the same settings
+    let sidecar_config;
+    let mut config = config;
+    let outcome = match image_config(input_path, config) {
+        Ok(merged) => {
+            if let Some(merged) = merged {
+                sidecar_config = merged;
+                config = &sidecar_config;
+            }
+            process_single_image(input_path, &source_metadata, index, config, &mut timings)
+        }
+        Err(e) => Err(e),
+    };
+
+    let result = match outcome {
+        Ok(outcome) => {
+            if let Some(quality) = outcome.low_quality {
+                warnings.push(format!("{} was encoded at quality {}, below {}; the size target may be too aggressive",
+                    input_path.display(), quality, config.warn_below_quality));
+            }
+            if let (Some(mismatch), Some((width, height))) = (outcome.mismatch, config.equal_output_size) {
+                if mismatch > SIGNIFICANT_MISMATCH {
+                    warnings.push(match config.equal_output_fit {
+                        EqualFit::Pad => format!("{}: {:.0}% of the {}x{} output is padding",
+                            input_path.display(), mismatch * 100.0, width, height),
+                        EqualFit::Crop => format!("{}: {:.0}% of the image was cropped away to fill {}x{}",
+                            input_path.display(), mismatch * 100.0, width, height),
+                    });
+                }
+            }
+            // Runs before the size is read, since optimizers usually rewrite the file in place
+            let mut post_exit_code = None;
+            if let Some(command) = &config.post_command {
+                match run_post_command(command, &outcome.output_path) {
+                    Ok(output) => {
+                        post_exit_code = output.status.code();
+                        if !output.status.success() {
+                            // The last line of stderr is usually the optimizer's own error message
+                            let stderr = String::from_utf8_lossy(&output.stderr);
+                            let reason = stderr.trim().lines().last().map(|line| format!(": {}", line)).unwrap_or_default();
+                            warnings.push(format!("--post-command failed for {} ({}){}",
+                                outcome.output_path.display(), output.status, reason));
+                        }
+                    }
+                    Err(e) => warnings.push(format!("--post-command could not start for {}: {}", outcome.output_path.display(), e)),
+                }
+            }
+            let final_size = match outcome.discarded_size {
+                Some(size) => Ok(size),
+                None => fs::metadata(&outcome.output_path).map(|metadata| metadata.len()),
+            };
+            match final_size {
+                Ok(final_size) => ProcessResult {
+                    input_path: input_path.to_path_buf(),
+                    output_path: outcome.output_path.clone(),
+                    original_size,
+                    final_size,
+                    success: true,
+                    message: match outcome.converted_from {
+                        _ if outcome.passed_through => "(passed through, at or under --recompress-only-if-larger-than)".to_string(),
+                        _ if outcome.kept_original => "(kept original, re-encoding was larger)".to_string(),
+                        Some(original) => format!("(converted, size target unreachable as {})", format_name(original)),
+                        None => aspect_note(outcome.dimensions, config).unwrap_or_default(),
+                    },
+                    format,
+                    kept_original: outcome.kept_original,
+                    passed_through: outcome.passed_through,
+                    rotated: outcome.rotated,
+                    below_quality: outcome.low_quality.is_some(),
+                    converted_from: outcome.converted_from,
+                    dimensions: Some(outcome.dimensions),
+                    thumbnail: outcome.thumbnail,
+                    metrics: outcome.metrics,
+                    post_exit_code,
+                    ..Default::default()
+                },
+                Err(e) => ProcessResult {
+                    input_path: input_path.to_path_buf(),
+                    output_path: outcome.output_path,
+                    original_size,
+                    final_size: 0,
+                    success: false,
+                    message: format!("Failed to read output file: {}", e),
+                    error_detail: error_detail(&e),
+                    format,
+                    ..Default::default()
+                },
+            }
+        }
+        Err(e) => ProcessResult {
+            input_path: input_path.to_path_buf(),
+            output_path: PathBuf::new(),
+            original_size,
+            final_size: 0,
+            success: false,
+            message: e.to_string(),
+            error_detail: error_detail(e.as_ref()),
+            undecodable: is_undecodable(e.as_ref()),
+            format,
+            ..Default::default()
+        },
+    };
+
+    ProcessResult { timings, warnings, ..result }
+}
+
+// Corrupt or unrecognized contents, as opposed to a failure of the resize or encode. Unsupported
+// color types come from encoders, so only an unknown format counts
+fn is_undecodable(error: &(dyn std::error::Error + 'static)) -> bool {
+    match error.downcast_ref::<image::ImageError>() {
+        Some(image::ImageError::Decoding(_)) => true,
+        Some(image::ImageError::Unsupported(e)) => matches!(e.kind(), image::error::UnsupportedErrorKind::Format(_)),
+        _ => false,
+    }
+}
+
+// The message only shows the outermost error; its sources often name the underlying decoder
+// or OS error, and the Debug form keeps the variants Display leaves out
+fn error_detail(error: &(dyn std::error::Error + 'static)) -> Vec<String> {
+    let mut detail = Vec::new();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        detail.push(format!("caused by: {}", cause));
+        source = cause.source();
+    }
+    detail.push(format!("debug: {:?}", error));
+    detail
+}
+
+// Arguments are passed straight to the program without a shell, so paths need no quoting;
+// without a {} the path is added as the last argument
+fn run_post_command(command: &[String], output_path: &Path) -> std::io::Result<std::process::Output> {
+    let path = output_path.to_string_lossy();
+    let mut args: Vec<String> = command[1..].iter().map(|arg| arg.replace("{}", &path)).collect();
+    if !command.iter().any(|word| word.contains("{}")) {
+        args.push(path.into_owned());
+    }
+    std::process::Command::new(&command[0])
+        .args(&args)
+        .stdin(std::process::Stdio::null())
+        .output()
+}
+
+// Every file under the input, images or not; callers filter with is_image_file
+fn collect_files(path: &Path, config: &Config) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    if let Some(pattern) = &config.input_glob {
+        return collect_glob(pattern, config);
+    }
+
+    let mut files = Vec::new();
+
+    if path.is_file() {
+        files.push(path.to_path_buf());
+    } else if path.is_dir() {
+        // An --output inside the input tree would otherwise be re-processed on the next run
+        let output_dir = config.output_dir.as_ref().and_then(|dir| fs::canonicalize(dir).ok());
+
+        // Prune whole subtrees, including our own default output folders from earlier runs
+        let walker = WalkDir::new(path).into_iter().filter_entry(|entry| {
+            if entry.depth() == 0 || !entry.file_type().is_dir() {
+                return true;
+            }
+            let name = entry.file_name().to_string_lossy();
+            if name == "resized" || config.exclude_dirs.iter().any(|dir| *dir == name) {
+                return false;
+            }
+            match &output_dir {
+                Some(output_dir) => fs::canonicalize(entry.path()).map_or(true, |dir| dir != *output_dir),
+                None => true,
+            }
+        });
+
+        for entry in walker.filter_map(|e| e.ok()) {
+            let path = entry.path();
+            if path.is_file() {
+                files.push(path.to_path_buf());
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+// Expanded here rather than by the shell, so patterns work the same on Windows. Folders below the
+// pattern's leading folder are pruned like in a directory walk
+fn collect_glob(pattern: &str, config: &Config) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let output_dir = config.output_dir.as_ref().and_then(|dir| fs::canonicalize(dir).ok());
+    let skipped = |dir: &Path| {
+        let name = dir.file_name().unwrap_or_default().to_string_lossy();
+        name == "resized"
+            || config.exclude_dirs.iter().any(|excluded| *excluded == name)
+            || output_dir.as_ref().is_some_and(|output_dir| fs::canonicalize(dir).is_ok_and(|dir| dir == *output_dir))
+    };
+
+    let mut files = Vec::new();
+    // Unreadable folders are skipped, as in the directory walk
+    for path in glob::glob(pattern)?.filter_map(|entry| entry.ok()) {
+        if !path.is_file() {
+            continue;
+        }
+        let mut below_base = path
+            .parent()
+            .into_iter()
+            .flat_map(Path::ancestors)
+            .take_while(|dir| *dir != config.input_path && !dir.as_os_str().is_empty());
+        if below_base.any(skipped) {
+            continue;
+        }
+        files.push(path);
+    }
+    Ok(files)
+}
+
+fn is_glob(input: &str) -> bool {
+    input.contains(['*', '?', '['])
+}
+
+// The folders before the first component with glob characters, e.g. photos for photos/**/*.jpg
+fn glob_base(pattern: &str) -> PathBuf {
+    let base: PathBuf = Path::new(pattern)
+        .components()
+        .take_while(|component| !is_glob(&component.as_os_str().to_string_lossy()))
+        .collect();
+    if base.as_os_str().is_empty() {
+        PathBuf::from(".")
+    } else {
+        base
+    }
+}
+
+// Paths piped in by --stdin-list, e.g. from find; anything that isn't an image file is skipped with a warning
+fn read_stdin_list(config: &Config) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let mut files = Vec::new();
+    for line in std::io::stdin().lines() {
+        let line = line?;
+        let line = line.trim_end_matches('\r');
+        if line.is_empty() {
+            continue;
+        }
+        let path = PathBuf::from(line);
+        if !path.is_file() {
+            println!("⚠️  Skipping {}: not a file", path.display());
+        } else if !is_image_file(&path, config) {
+            println!("⚠️  Skipping {}: not a supported image", path.display());
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+// Copy each file next to where its images' outputs go, returning the error for each that failed
+// Scripts reading --summary-json still get an object, with every total at zero. Other files are
+// still mirrored by --copy-unsupported; with no images there is nothing for --max-files to stop
+fn finish_without_images(
+    message: &str,
+    unsupported: &[PathBuf],
+    config: &Config,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let copied = if config.copy_unsupported && !config.plan && !config.collect_stats {
+        copy_unsupported(unsupported, config)
+    } else {
+        Vec::new()
+    };
+    if config.summary_json {
+        println!("{}", serde_json::to_string(&BatchSummary::default())?);
+    } else if !config.quiet {
+        println!("{}", message);
+        if config.copy_unsupported {
+            print_copied(&copied);
+        }
+    }
+    Ok(())
+}
+
+fn print_copied(copied: &[Result<(), String>]) {
+    let failures: Vec<&String> = copied.iter().filter_map(|r| r.as_ref().err()).collect();
+    println!("\n📁 Copied {} unsupported file(s) unchanged", copied.len() - failures.len());
+    for failure in failures {
+        println!("  ✗ {}", failure);
+    }
+}
+
+fn copy_unsupported(files: &[PathBuf], config: &Config) -> Vec<Result<(), String>> {
+    let mut targets = BTreeSet::new();
+    files
+        .iter()
+        // Sidecars configure this tool rather than being content to mirror
+        .filter(|path| !path.to_string_lossy().ends_with(SIDECAR_SUFFIX))
+        .map(|path| {
+            let target = copy_target(path, config);
+            // Only files from outside the input folder, e.g. listed by --stdin-list, can share a target
+            if !targets.insert(target.clone()) {
+                return Err(format!("{} - another file was already copied to {}", path.display(), target.display()));
+            }
+            // Copying a file onto itself would truncate it, e.g. when --output is the input folder
+            if same_file(path, &target) {
+                return Ok(());
+            }
+            fs::create_dir_all(target.parent().unwrap_or(Path::new(".")))
+                .and_then(|_| fs::copy(path, &target))
+                .map(|_| ())
+                .map_err(|e| format!("{} - {}", path.display(), e))
+        })
+        .collect()
+}
+
+// Mirrors the input tree under --output; without it the copy joins the resized folder next to the file
+fn copy_target(path: &Path, config: &Config) -> PathBuf {
+    let file_name = path.file_name().unwrap_or_default();
+    let Some(output_dir) = &config.output_dir else {
+        return output_dir_for(path, config).join(file_name);
+    };
+    // A leading "." is dropped from both, since walked paths keep it and globbed ones don't
+    let root = config.input_path.strip_prefix(".").unwrap_or(&config.input_path);
+    match path.strip_prefix(".").unwrap_or(path).strip_prefix(root) {
+        Ok(relative) if !relative.as_os_str().is_empty() => output_dir.join(relative),
+        _ => output_dir.join(file_name),
+    }
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+fn has_resized_suffix(path: &Path) -> bool {
+    path.file_stem()
+        .is_some_and(|stem| stem.to_string_lossy().ends_with(RESIZED_SUFFIX))
+}
+
+fn is_image_file(path: &Path, config: &Config) -> bool {
+    if config.raw_input.is_some() && is_raw_file(path) {
+        return true;
+    }
+    match path.extension() {
+        Some(ext) => {
+            let ext = ext.to_string_lossy().to_lowercase();
+            match &config.input_formats {
+                Some(formats) => formats.contains(&ext),
+                None => DEFAULT_INPUT_FORMATS.contains(&ext.as_str()),
+            }
+        }
+        None => false,
+    }
+}
+
+fn is_raw_file(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| RAW_EXTENSIONS.contains(&ext.to_string_lossy().to_lowercase().as_str()))
+}
+
+fn decode_input(input_path: &Path, config: &Config) -> Result<DynamicImage, Box<dyn std::error::Error>> {
+    if let Some((width, height)) = config.raw_input.filter(|_| is_raw_file(input_path)) {
+        let data = fs::read(input_path)?;
+        let expected = width as usize * height as usize * 4;
+        if data.len() != expected {
+            return Err(format!(
+                "{} is {} bytes, but {}x{} RGBA pixels need {} (check --raw-input)",
+                input_path.display(),
+                data.len(),
+                width,
+                height,
+                expected
+            )
+            .into());
+        }
+        return Ok(DynamicImage::ImageRgba8(
+            image::RgbaImage::from_raw(width, height, data).ok_or("Invalid raw image")?,
+        ));
+    }
+    if let Some(index) = config.frame {
+        if let Some(img) = decode_frame(input_path, index)? {
+            return Ok(img);
+        }
+    }
+    if config.stream_decode {
+        if let Some(img) = stream_decode(input_path, config)? {
+            return Ok(img);
+        }
+    }
+    // Decode by content so mislabeled files still load; the extension only picks the output format
+    let data = fs::read(input_path)?;
+    if image::guess_format(&data).ok() == Some(ImageFormat::Jpeg) {
+        if let Some(img) = decode_cmyk_jpeg(&data)? {
+            return Ok(img);
+        }
+    }
+    Ok(image::load_from_memory(&data)?)
+}
+
+// Shrinks PNG and TIFF inputs by a whole factor while they are read, so only the reduced image
+// is ever held in memory; the usual resize then works from that. None when the format, its
+// layout or the options need the full-size pixels
+fn stream_decode(input_path: &Path, config: &Config) -> Result<Option<DynamicImage>, Box<dyn std::error::Error>> {
+    // These look at or transform full-resolution pixels before the resize
+    if config.trim.is_some() || config.to_srgb || config.normalize_orientation.is_some() {
+        return Ok(None);
+    }
+    let reader = || fs::File::open(input_path).map(std::io::BufReader::new);
+    let img = match detect_format(input_path) {
+        Some(ImageFormat::Png) => stream_png(reader()?, config)?,
+        Some(ImageFormat::Tiff) => stream_tiff(reader()?, config)?,
+        _ => None,
+    };
+    if let (Some(img), true) = (&img, config.verbose) {
+        log_verbose(format!("  Stream-decoded to {}x{}", img.width(), img.height()));
+    }
+    Ok(img)
+}
+
+// Whole factor to shrink a width x height source by while decoding, if it is at least 2
+fn stream_factor(width: u32, height: u32, config: &Config) -> Option<u32> {
+    let (width_f, height_f) = (width as f64, height as f64);
+    // The largest per-axis scale the resize will ask for
+    let scale = if let Some((target_width, target_height)) = config.dimensions {
+        let (x, y) = (target_width as f64 / width_f, target_height as f64 / height_f);
+        if config.maintain_aspect_ratio || config.letterbox { x.min(y) } else { x.max(y) }
+    } else if let Some(megapixels) = config.megapixels {
+        (megapixels * 1_000_000.0 / (width_f * height_f)).sqrt()
+    } else {
+        match config.edge? {
+            Edge::Long(pixels) => pixels as f64 / width_f.max(height_f),
+            Edge::Short(pixels) => pixels as f64 / width_f.min(height_f),
+        }
+    };
+    let factor = (1.0 / (scale * STREAM_OVERSAMPLE)).floor();
+    (factor >= 2.0).then(|| (factor as u32).min(width).min(height))
+}
+
+fn stream_png(reader: impl Read, config: &Config) -> Result<Option<DynamicImage>, Box<dyn std::error::Error>> {
+    let mut decoder = png::Decoder::new_with_limits(reader, png::Limits { bytes: usize::MAX });
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info()?;
+    let (width, height) = (reader.info().width, reader.info().height);
+    // Interlaced rows arrive in seven passes, so they can't be reduced one at a time
+    let Some(factor) = stream_factor(width, height, config).filter(|_| !reader.info().interlaced) else {
+        return Ok(None);
+    };
+
+    let channels = reader.output_color_type().0.samples();
+    let mut reducer = BoxReducer::new(width, height, factor, channels);
+    while let Some(row) = reader.next_row()? {
+        reducer.push_row(row.data());
+    }
+    Ok(reducer.finish())
+}
+
+fn stream_tiff(reader: impl Read + std::io::Seek, config: &Config) -> Result<Option<DynamicImage>, Box<dyn std::error::Error>> {
+    use tiff::decoder::{Decoder, DecodingResult, Limits};
+    use tiff::tags::Tag;
+
+    let mut decoder = Decoder::new(reader)?.with_limits(Limits::unlimited());
+    let (width, height) = decoder.dimensions()?;
+    let channels = match decoder.colortype()? {
+        tiff::ColorType::Gray(8 | 16) => 1,
+        tiff::ColorType::GrayA(8 | 16) => 2,
+        tiff::ColorType::RGB(8 | 16) => 3,
+        tiff::ColorType::RGBA(8 | 16) => 4,
+        _ => return Ok(None),
+    };
+    // Separate planes store each channel in its own strips, which can't be combined row by row
+    let planar = decoder.find_tag_unsigned::<u16>(Tag::PlanarConfiguration)?.unwrap_or(1) != 1;
+    let Some(factor) = stream_factor(width, height, config).filter(|_| !planar) else {
+        return Ok(None);
+    };
+
+    // Strips span the full width; tiles are read one row of tiles at a time and stitched
+    let (chunk_width, chunk_height) = decoder.chunk_dimensions();
+    let across = width.div_ceil(chunk_width);
+    let row_bytes = width as usize * channels;
+    let mut reducer = BoxReducer::new(width, height, factor, channels);
+    for chunk_row in 0..height.div_ceil(chunk_height) {
+        let mut band = Vec::new();
+        for column in 0..across {
+            let index = chunk_row * across + column;
+            let (data_width, data_height) = decoder.chunk_data_dimensions(index);
+            let samples: Vec<u8> = match decoder.read_chunk(index)? {
+                DecodingResult::U8(data) => data,
+                DecodingResult::U16(data) => data.iter().map(|&v| (v >> 8) as u8).collect(),
+                _ => return Ok(None),
+            };
+            if band.is_empty() {
+                band = vec![0; row_bytes * data_height as usize];
+            }
+            let chunk_bytes = data_width as usize * channels;
+            let left = (column * chunk_width) as usize * channels;
+            for (y, line) in samples.chunks_exact(chunk_bytes).enumerate() {
+                band[y * row_bytes + left..][..chunk_bytes].copy_from_slice(line);
+            }
+        }
+        for row in band.chunks_exact(row_bytes) {
+            reducer.push_row(row);
+        }
+    }
+    Ok(reducer.finish())
+}
+
+// Averages factor x factor blocks of 8-bit samples as rows arrive, holding one output row of sums.
+// Up to factor - 1 leftover source pixels at the right and bottom edges are dropped
+struct BoxReducer {
+    factor: u32,
+    channels: usize,
+    width: u32,
+    height: u32,
+    rows_seen: u32,
+    sums: Vec<u32>,
+    pixels: Vec<u8>,
+}
+
+impl BoxReducer {
+    fn new(source_width: u32, source_height: u32, factor: u32, channels: usize) -> Self {
+        let (width, height) = (source_width / factor, source_height / factor);
+        BoxReducer {
+            factor,
+            channels,
+            width,
+            height,
+            rows_seen: 0,
+            sums: vec![0; width as usize * channels],
+            pixels: Vec::with_capacity(width as usize * height as usize * channels),
+        }
+    }
+
+    fn push_row(&mut self, row: &[u8]) {
+        if self.rows_seen / self.factor >= self.height {
+            return;
+        }
+        let block = self.factor as usize * self.channels;
+        for (sums, samples) in self.sums.chunks_exact_mut(self.channels).zip(row.chunks_exact(block)) {
+            for pixel in samples.chunks_exact(self.channels) {
+                for (sum, &sample) in sums.iter_mut().zip(pixel) {
+                    *sum += u32::from(sample);
+                }
+            }
+        }
+        self.rows_seen += 1;
+        if self.rows_seen.is_multiple_of(self.factor) {
+            let area = self.factor * self.factor;
+            self.pixels.extend(self.sums.iter().map(|&sum| ((sum + area / 2) / area) as u8));
+            self.sums.fill(0);
+        }
+    }
+
+    fn finish(self) -> Option<DynamicImage> {
+        let (width, height, pixels) = (self.width, self.height, self.pixels);
+        match self.channels {
+            1 => image::GrayImage::from_raw(width, height, pixels).map(DynamicImage::ImageLuma8),
+            2 => image::GrayAlphaImage::from_raw(width, height, pixels).map(DynamicImage::ImageLumaA8),
+            3 => image::RgbImage::from_raw(width, height, pixels).map(DynamicImage::ImageRgb8),
+            4 => image::RgbaImage::from_raw(width, height, pixels).map(DynamicImage::ImageRgba8),
+            _ => None,
+        }
+    }
+}
+
+// Print workflows hand over CMYK and YCCK JPEGs, which image converts without their ICC
+// profile and with YCCK colors inverted; None for every other JPEG
+fn decode_cmyk_jpeg(data: &[u8]) -> Result<Option<DynamicImage>, Box<dyn std::error::Error>> {
+    use lcms2::{Intent, PixelFormat, Profile, Transform};
+
+    let mut decoder = jpeg_decoder::Decoder::new(data);
+    decoder.read_info()?;
+    let info = decoder.info().ok_or("Missing JPEG header")?;
+    if info.pixel_format != jpeg_decoder::PixelFormat::CMYK32 {
+        return Ok(None);
+    }
+    let mut cmyk = decoder.decode()?;
+
+    // jpeg-decoder undoes Adobe's inverted storage for every file, which leaves plain CMYK
+    // inverted, and for YCCK it only undoes it on the K channel
+    match adobe_color_transform(data) {
+        Some(ADOBE_YCCK) => cmyk.chunks_exact_mut(4).for_each(|pixel| pixel[..3].iter_mut().for_each(|v| *v = 255 - *v)),
+        Some(_) => {}
+        None => cmyk.iter_mut().for_each(|v| *v = 255 - *v),
+    }
+
+    let profile = decoder
+        .icc_profile()
+        .and_then(|icc| Profile::new_icc(&icc).ok())
+        .filter(|profile| profile.color_space() == lcms2::ColorSpaceSignature::CmykData);
+    let rgb: Vec<u8> = match profile {
+        Some(profile) => {
+            let transform = Transform::new(&profile, PixelFormat::CMYK_8, &Profile::new_srgb(), PixelFormat::RGB_8, Intent::Perceptual)?;
+            let mut rgb = vec![[0u8; 3]; cmyk.len() / 4];
+            transform.transform_pixels(cmyk.as_chunks::<4>().0, &mut rgb);
+            rgb.concat()
+        }
+        // Without a profile, fall back to the naive ink model
+        None => cmyk
+            .chunks_exact(4)
+            .flat_map(|pixel| {
+                let white = 255 - u16::from(pixel[3]);
+                pixel[..3].iter().map(move |&ink| ((255 - u16::from(ink)) * white / 255) as u8)
+            })
+            .collect(),
+    };
+
+    let img = image::RgbImage::from_raw(u32::from(info.width), u32::from(info.height), rgb).ok_or("Invalid CMYK image")?;
+    Ok(Some(DynamicImage::ImageRgb8(img)))
+}
+
+// Transform byte of the Adobe APP14 segment, found by walking the markers up to the scan
+fn adobe_color_transform(data: &[u8]) -> Option<u8> {
+    let mut pos = 2;
+    while pos + 4 <= data.len() && data[pos] == 0xFF {
+        let marker = data[pos + 1];
+        if marker == 0xDA {
+            break;
+        }
+        let length = u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+        let segment = data.get(pos + 4..pos + 2 + length)?;
+        if marker == 0xEE && segment.len() >= 12 && segment.starts_with(b"Adobe") {
+            return Some(segment[11]);
+        }
+        pos += 2 + length;
+    }
+    None
+}
+
+fn process_single_image(
+    input_path: &Path,
+    source_metadata: &fs::Metadata,
+    index: usize,
+    config: &Config,
+    timings: &mut StageTimings,
+) -> Result<ProcessOutcome, Box<dyn std::error::Error>> {
+    if passes_through(input_path, source_metadata.len(), config) {
+        return pass_through(input_path, source_metadata, index, config, timings);
+    }
+
+    let result = render_image(input_path, config, timings)?;
+
+    // The original keeps its name, so its contents must keep their format
+    if config.overwrite_input && get_image_format(input_path).ok() != Some(result.format) {
+        return Err(format!(
+            "--overwrite-input would store {} data in {}, leaving the original untouched",
+            format_name(result.format),
+            input_path.display()
+        )
+        .into());
+    }
+
+    // Determine output path
+    let output_path = get_output_path(input_path, &result, index, config)?;
+
+    // Never let the output grow past the input when asked to keep the smaller file
+    let kept_original = config.keep_smaller
+        && input_format(input_path) == Some(result.format)
+        && result.data.len() as u64 > fs::metadata(input_path)?.len();
+
+    // Thumbnail the encoded bytes so the sheet shows the output without reading it back from disk
+    let thumbnail = match &config.contact_sheet {
+        Some(_) => Some(
+            image::load_from_memory(&result.data)?
+                .thumbnail(CONTACT_SHEET_CELL, CONTACT_SHEET_CELL)
+                .to_rgba8(),
+        ),
+        None => None,
+    };
+
+    let metrics = if config.metrics {
+        // Measured before saving, since --overwrite-input replaces the original
+        let output = if kept_original {
+            decode_input(input_path, config)?
+        } else {
+            image::load_from_memory(&result.data)?
+        };
+        let original = decode_input(input_path, config)?;
+        let metrics = measure_quality(&original, &output);
+        if config.verbose {
+            log_verbose(format!("  → SSIM: {:.4}, PSNR: {:.2} dB", metrics.ssim, metrics.psnr));
+        }
+        Some(metrics)
+    } else {
+        None
+    };
+
+    // Save the result
+    let started = Instant::now();
+    let dimensions = if config.no_write {
+        (result.width, result.height)
+    } else if kept_original {
+        // The output name can match the input when re-deriving a _resized name in place
+        if !same_file(input_path, &output_path) {
+            fs::copy(input_path, &output_path)?;
+        }
+        image::image_dimensions(input_path)?
+    } else if config.overwrite_input {
+        let expected = config.validate_output.then_some((result.width, result.height));
+        replace_file(&output_path, &result.data, expected, config.output_temp_dir.as_deref())?;
+        (result.width, result.height)
+    } else {
+        fs::write(&output_path, &result.data)?;
+        (result.width, result.height)
+    };
+    if config.preserve_permissions || config.preserve_mtime || config.copy_timestamps {
+        copy_file_metadata(source_metadata, &output_path, config)?;
+    }
+    timings.write = started.elapsed();
+
+    // Replaced originals were validated before the rename, and deleting them here would lose the image
+    if config.validate_output && !config.overwrite_input {
+        if let Err(e) = validate_output(&output_path, dimensions) {
+            // A missing file is easier to notice downstream than a corrupt one
+            let _ = fs::remove_file(&output_path);
+            return Err(e);
+        }
+    }
+    
+    if config.verbose && config.target_size_kb.is_some() {
+        log_verbose(format!("  → Final quality: {}, Scale: {:.0}%, Size: {}x{}", 
+            result.quality, 
+            result.scale * 100.0,
+            dimensions.0,
+            dimensions.1
+        ));
+    }
+
+    // Lossless encoders read quality as effort, so only lossy outputs can look degraded
+    let lossy = match result.format {
+        _ if config.raw_output => false,
+        ImageFormat::Jpeg => true,
+        ImageFormat::WebP => config.near_lossless.is_none() && !keeps_lossless_webp(input_path, config),
+        _ => false,
+    };
+    let low_quality = (lossy && !kept_original && result.quality < config.warn_below_quality).then_some(result.quality);
+
+    Ok(ProcessOutcome {
+        output_path,
+        kept_original,
+        dimensions,
+        thumbnail,
+        metrics,
+        low_quality,
+        passed_through: false,
+        rotated: result.rotated,
+        mismatch: result.mismatch,
+        converted_from: result.converted_from,
+        discarded_size: config.no_write.then_some(result.data.len() as u64),
+    })
+}
+
+// -r fits inside the -d box, so one side usually comes out smaller than asked for
+fn aspect_note((width, height): (u32, u32), config: &Config) -> Option<String> {
+    let (box_width, box_height) = config.dimensions.filter(|_| config.maintain_aspect_ratio && !config.letterbox)?;
+    ((width, height) != (box_width, box_height))
+        .then(|| format!("(aspect ratio kept, requested {}x{})", box_width, box_height))
+}
+
+// Decodes by content like decode_input, so mislabeled files report their real size
+fn header_dimensions(path: &Path) -> Option<(u32, u32)> {
+    image::io::Reader::open(path).ok()?.with_guessed_format().ok()?.into_dimensions().ok()
+}
+
+// Raw pixel buffers have no format to keep, so they are always converted
+fn passes_through(path: &Path, size: u64, config: &Config) -> bool {
+    config.recompress_above_kb.is_some_and(|kb| size <= kb * 1024)
+        && !(config.raw_input.is_some() && is_raw_file(path))
+        // Copied bytes can't become the format an --output file asks for
+        && config.output_file.as_deref().is_none_or(|file| get_image_format(file).ok() == input_format(path))
+}
+
+// Copies a small input into the output tree under the name processing would have given it,
+// without decoding more than its header
+fn pass_through(
+    input_path: &Path,
+    source_metadata: &fs::Metadata,
+    index: usize,
+    config: &Config,
+    timings: &mut StageTimings,
+) -> Result<ProcessOutcome, Box<dyn std::error::Error>> {
+    let format = detect_format(input_path).map_or_else(|| get_image_format(input_path), Ok)?;
+    let (width, height) = header_dimensions(input_path).ok_or("Could not read the image dimensions")?;
+    let source = CompressionResult {
+        data: Vec::new(),
+        quality: default_quality(format, config),
+        scale: 1.0,
+        format,
+        width,
+        height,
+        converted_from: None,
+        rotated: false,
+        mismatch: None,
+    };
+    let output_path = get_output_path(input_path, &source, index, config)?;
+
+    let thumbnail = match &config.contact_sheet {
+        Some(_) => Some(decode_input(input_path, config)?.thumbnail(CONTACT_SHEET_CELL, CONTACT_SHEET_CELL).to_rgba8()),
+        None => None,
+    };
+
+    let started = Instant::now();
+    if !config.no_write && !same_file(input_path, &output_path) {
+        fs::copy(input_path, &output_path)?;
+        if config.preserve_permissions || config.preserve_mtime || config.copy_timestamps {
+            copy_file_metadata(source_metadata, &output_path, config)?;
+        }
+    }
+    timings.write = started.elapsed();
+
+    Ok(ProcessOutcome {
+        output_path,
+        kept_original: false,
+        passed_through: true,
+        dimensions: (width, height),
+        thumbnail,
+        metrics: None,
+        low_quality: None,
+        converted_from: None,
+        rotated: false,
+        mismatch: None,
+        discarded_size: config.no_write.then_some(source_metadata.len()),
+    })
+}
+
+fn measure_quality(original: &DynamicImage, output: &DynamicImage) -> QualityMetrics {
+    let (width, height) = output.dimensions();
+    let original = if original.dimensions() == (width, height) {
+        original.to_rgb8()
+    } else {
+        original.resize_exact(width, height, image::imageops::FilterType::Lanczos3).to_rgb8()
+    };
+    let output = output.to_rgb8();
+
+    let squared_error: f64 = original
+        .as_raw()
+        .iter()
+        .zip(output.as_raw())
+        .map(|(&a, &b)| (f64::from(a) - f64::from(b)).powi(2))
+        .sum();
+    let mse = squared_error / original.as_raw().len().max(1) as f64;
+    let psnr = if mse == 0.0 {
+        MAX_PSNR
+    } else {
+        (10.0 * (255.0 * 255.0 / mse).log10()).min(MAX_PSNR)
+    };
+
+    QualityMetrics {
+        ssim: ssim(&luma(&original), &luma(&output), width, height),
+        psnr,
+    }
+}
+
+// BT.601 luma, the channel SSIM is conventionally measured on
+fn luma(img: &image::RgbImage) -> Vec<f64> {
+    img.pixels()
+        .map(|p| 0.299 * f64::from(p[0]) + 0.587 * f64::from(p[1]) + 0.114 * f64::from(p[2]))
+        .collect()
+}
+
+// Mean SSIM over windows overlapping by half, shrunk to the whole image when it is smaller
+fn ssim(a: &[f64], b: &[f64], width: u32, height: u32) -> f64 {
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+
+    let window_width = SSIM_WINDOW.min(width) as usize;
+    let window_height = SSIM_WINDOW.min(height) as usize;
+    let (width, height) = (width as usize, height as usize);
+    let step = (SSIM_WINDOW as usize / 2).max(1);
+
+    let mut total = 0.0;
+    let mut windows = 0;
+    for top in (0..=height - window_height).step_by(step) {
+        for left in (0..=width - window_width).step_by(step) {
+            let (mut sum_a, mut sum_b, mut sum_aa, mut sum_bb, mut sum_ab) = (0.0, 0.0, 0.0, 0.0, 0.0);
+            for y in top..top + window_height {
+                for x in left..left + window_width {
+                    let (va, vb) = (a[y * width + x], b[y * width + x]);
+                    sum_a += va;
+                    sum_b += vb;
+                    sum_aa += va * va;
+                    sum_bb += vb * vb;
+                    sum_ab += va * vb;
+                }
+            }
+            let n = (window_width * window_height) as f64;
+            let (mean_a, mean_b) = (sum_a / n, sum_b / n);
+            let var_a = sum_aa / n - mean_a * mean_a;
+            let var_b = sum_bb / n - mean_b * mean_b;
+            let covariance = sum_ab / n - mean_a * mean_b;
+            total += ((2.0 * mean_a * mean_b + C1) * (2.0 * covariance + C2))
+                / ((mean_a * mean_a + mean_b * mean_b + C1) * (var_a + var_b + C2));
+            windows += 1;
         }
     }
+    total / windows as f64
+}
 
+fn copy_file_metadata(source: &fs::Metadata, output_path: &Path, config: &Config) -> std::io::Result<()> {
+    if config.preserve_mtime || config.copy_timestamps {
+        let mut times = fs::FileTimes::new().set_modified(source.modified()?);
+        if config.copy_timestamps {
+            times = times.set_accessed(source.accessed()?);
+        }
+        // Opened for writing so Windows allows the timestamp change; the contents are untouched
+        fs::OpenOptions::new()
+            .write(true)
+            .open(output_path)?
+            .set_times(times)?;
+    }
+    // Last, so a read-only source mode doesn't block setting the timestamp
+    if config.preserve_permissions {
+        fs::set_permissions(output_path, source.permissions())?;
+    }
     Ok(())
 }
 
-fn process_single_image_with_result(input_path: &Path, config: &Config) -> ProcessResult {
-    let original_size = match fs::metadata(input_path) {
-        Ok(metadata) => metadata.len(),
-        Err(e) => {
-            return ProcessResult {
-                input_path: input_path.to_path_buf(),
-                output_path: PathBuf::new(),
-                original_size: 0,
-                final_size: 0,
-                success: false,
-                message: format!("Failed to read file metadata: {}", e),
-            };
+// Numbers the temporary files in --output-temp-dir, which parallel workers share
+static TEMP_FILES: AtomicUsize = AtomicUsize::new(0);
+
+// Written next to the original (or in --output-temp-dir) and renamed over it, so a crash never
+// leaves a half-written image
+fn replace_file(
+    path: &Path,
+    data: &[u8],
+    expected: Option<(u32, u32)>,
+    temp_dir: Option<&Path>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let temp_path = match temp_dir {
+        // Originals in different folders can share a name, so each temporary file gets its own
+        Some(dir) => {
+            let mut temp_name = std::ffi::OsString::from(".");
+            temp_name.push(path.file_name().unwrap_or_default());
+            temp_name.push(format!(".{}.{}.resizing", std::process::id(), TEMP_FILES.fetch_add(1, Ordering::Relaxed)));
+            dir.join(temp_name)
         }
+        None => sibling_temp_path(path),
     };
 
-    match process_single_image(input_path, config) {
-        Ok(output_path) => {
-            match fs::metadata(&output_path) {
-                Ok(metadata) => ProcessResult {
-                    input_path: input_path.to_path_buf(),
-                    output_path: output_path.clone(),
-                    original_size,
-                    final_size: metadata.len(),
-                    success: true,
-                    message: String::new(),
-                },
-                Err(e) => ProcessResult {
-                    input_path: input_path.to_path_buf(),
-                    output_path,
-                    original_size,
-                    final_size: 0,
-                    success: false,
-                    message: format!("Failed to read output file: {}", e),
-                },
+    // Replaced in place, the file keeps its mode, so a private 0600 original doesn't come back with the
+    // default mode. Set before writing so the data is never readable under a looser one
+    let permissions = fs::metadata(path)?.permissions();
+    let written = fs::File::create(&temp_path).and_then(|mut file| {
+        file.set_permissions(permissions)?;
+        std::io::Write::write_all(&mut file, data)?;
+        file.sync_all()
+    });
+    let checked = match (written, expected) {
+        (Err(e), _) => Err(e.into()),
+        (Ok(()), Some(expected)) => validate_output(&temp_path, expected),
+        (Ok(()), None) => Ok(()),
+    };
+    if let Err(e) = checked.and_then(|()| move_into_place(&temp_path, path).map_err(Into::into)) {
+        let _ = fs::remove_file(&temp_path);
+        return Err(e);
+    }
+    Ok(())
+}
+
+fn sibling_temp_path(path: &Path) -> PathBuf {
+    let mut temp_name = std::ffi::OsString::from(".");
+    temp_name.push(path.file_name().unwrap_or_default());
+    temp_name.push(".resizing");
+    path.with_file_name(temp_name)
+}
+
+// A rename is only atomic within one filesystem, so a temporary file on another mount is first
+// copied next to the destination and renamed from there
+fn move_into_place(temp_path: &Path, path: &Path) -> std::io::Result<()> {
+    match fs::rename(temp_path, path) {
+        Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {
+            let sibling = sibling_temp_path(path);
+            let copied = fs::copy(temp_path, &sibling)
+                .and_then(|_| fs::File::open(&sibling)?.sync_all())
+                .and_then(|()| fs::rename(&sibling, path));
+            if copied.is_err() {
+                let _ = fs::remove_file(&sibling);
             }
+            let _ = fs::remove_file(temp_path);
+            copied
         }
-        Err(e) => ProcessResult {
-            input_path: input_path.to_path_buf(),
-            output_path: PathBuf::new(),
-            original_size,
-            final_size: 0,
-            success: false,
-            message: e.to_string(),
-        },
+        result => result,
+    }
+}
+
+fn validate_output(path: &Path, expected: (u32, u32)) -> Result<(), Box<dyn std::error::Error>> {
+    // Decode by content, since a temporary name for --overwrite-input has no image extension
+    let decoded = image::io::Reader::open(path)
+        .and_then(|reader| reader.with_guessed_format())
+        .map_err(|e| format!("Output failed validation, could not read: {}", e))?
+        .decode()
+        .map_err(|e| format!("Output failed validation, could not decode: {}", e))?;
+    if decoded.dimensions() != expected {
+        return Err(format!(
+            "Output failed validation, decoded as {}x{} instead of {}x{}",
+            decoded.width(),
+            decoded.height(),
+            expected.0,
+            expected.1
+        )
+        .into());
+    }
+    Ok(())
+}
+
+fn render_image(
+    input_path: &Path,
+    config: &Config,
+    timings: &mut StageTimings,
+) -> Result<CompressionResult, Box<dyn std::error::Error>> {
+    // Single-frame GIFs and WebPs fall through and are decoded again as still images
+    if config.animated_webp {
+        let started = Instant::now();
+        let frames = decode_animation(input_path)?;
+        timings.decode = started.elapsed();
+        if frames.len() > 1 {
+            return render_animation(frames, config, timings);
+        }
+    }
+
+    let started = Instant::now();
+    let mut img = decode_input(input_path, config)?;
+    timings.decode = started.elapsed();
+
+    // Without an embedded profile the pixels are assumed to already be sRGB
+    if config.to_srgb {
+        let started = Instant::now();
+        if let Some(icc) = read_icc_profile(input_path) {
+            img = convert_to_srgb(img, &icc)?;
+        }
+        timings.color = started.elapsed();
+    }
+
+    let rotated = needs_rotation(&img, config);
+    if rotated {
+        img = img.rotate90();
+    }
+    
+    if let Some(tolerance) = config.trim {
+        if let Some((left, top, width, height)) = find_trim(&img, tolerance) {
+            if config.verbose {
+                log_verbose(format!("  Trimmed border: {}px left, {}px top, {}px right, {}px bottom",
+                    left,
+                    top,
+                    img.width() - left - width,
+                    img.height() - top - height
+                ));
+            }
+            img = img.crop_imm(left, top, width, height);
+        }
+    }
+
+    let mismatch = equal_size_mismatch(img.width(), img.height(), config);
+    let started = Instant::now();
+    img = resize_for_config(img, config)?;
+    timings.resize = started.elapsed();
+
+    if let Some(palette) = &config.palette {
+        img = remap_to_palette(&img, palette, config.dither);
+    }
+
+    // Icons are packed at fixed sizes, so there is no size target to search for
+    if let Some(sizes) = &config.ico_sizes {
+        let started = Instant::now();
+        let data = encode_ico(&img, sizes, config.maintain_aspect_ratio, config.downscale_only, config.resampling)?;
+        timings.encode = started.elapsed();
+        let largest = sizes.iter().copied().max().unwrap_or(0);
+        return Ok(CompressionResult {
+            data,
+            quality: 100,
+            scale: 1.0,
+            format: ImageFormat::Ico,
+            width: largest,
+            height: largest,
+            converted_from: None,
+            rotated,
+            mismatch,
+        });
+    }
+
+    // The decoder only returns the first GIF frame, which becomes the poster image
+    let format = if let Some(output_file) = &config.output_file {
+        // -o out.jpg picks the format, whatever the input is
+        get_image_format(output_file)?
+    } else if config.flatten_gif && input_format(input_path) == Some(ImageFormat::Gif) {
+        ImageFormat::Png
+    } else if config.raw_input.is_some() && is_raw_file(input_path) {
+        // Raw pixels have no container to keep, so they are saved as PNG unless --raw-output
+        ImageFormat::Png
+    } else {
+        // Unusual extensions allowed by --input-formats keep the format found in the file
+        get_image_format(input_path)
+            .ok()
+            .or_else(|| detect_format(input_path))
+            .ok_or("Unsupported image format")?
+    };
+
+    // Grayscale and palette sources come out with the same channel layout as everything else
+    img = match config.force_color {
+        Some(ForceColor::Rgba) if format != ImageFormat::Jpeg => DynamicImage::ImageRgba8(img.to_rgba8()),
+        // JPEG can't store alpha, so RGBA is flattened the same way as RGB
+        Some(_) => DynamicImage::ImageRgb8(flatten_onto(&img, config.background)),
+        // Transparency converted to JPEG by an --output file is blended the same way
+        None if format == ImageFormat::Jpeg && img.color().has_alpha() => {
+            DynamicImage::ImageRgb8(flatten_onto(&img, config.background))
+        }
+        None => img,
+    };
+
+    // Nothing is encoded, so there is no quality to pick; the file is named .raw by get_output_path
+    if config.raw_output {
+        return Ok(CompressionResult {
+            data: img.to_rgba8().into_raw(),
+            quality: 100,
+            scale: 1.0,
+            format,
+            width: img.width(),
+            height: img.height(),
+            converted_from: None,
+            rotated,
+            mismatch,
+        });
+    }
+
+    // Re-encoding a lossless WebP lossily would silently throw away its fidelity
+    let lossless_config;
+    let mut config = config;
+    if format == ImageFormat::WebP && keeps_lossless_webp(input_path, config) {
+        lossless_config = Config {
+            webp_lossless: true,
+            ..config.clone()
+        };
+        config = &lossless_config;
+    }
+    
+    // If no target size specified, just encode with default quality
+    let target_bytes = match size_band(config) {
+        Some((_, upper)) => upper,
+        None => {
+            let quality = default_quality(format, config);
+            let started = Instant::now();
+            let data = save_to_buffer(&img, format, quality, config)?;
+            timings.encode = started.elapsed();
+            return Ok(CompressionResult {
+                data,
+                quality,
+                scale: 1.0,
+                format,
+                width: img.width(),
+                height: img.height(),
+                converted_from: None,
+                rotated,
+                mismatch,
+            });
+        }
+    };
+
+    // Smart compression algorithm
+    let started = Instant::now();
+    let mut result = smart_compress(&img, target_bytes, format, config)?;
+
+    if let Some(fallback) = config.fallback_format.filter(|&fallback| result.is_none() && fallback != format) {
+        if config.verbose {
+            log_verbose(format!("  Target unreachable as {}, retrying as {}", format_name(format), format_name(fallback)));
+        }
+        // Transparent areas get the background instead of whatever color hides under them
+        let img = if fallback == ImageFormat::Jpeg && img.color().has_alpha() {
+            DynamicImage::ImageRgb8(flatten_onto(&img, config.background))
+        } else {
+            img
+        };
+        result = smart_compress(&img, target_bytes, fallback, config)?
+            .map(|converted| CompressionResult { converted_from: Some(format), ..converted });
+    }
+    timings.compress = started.elapsed();
+
+    result
+        .map(|result| CompressionResult { rotated, mismatch, ..result })
+        .ok_or_else(|| {
+            // Name the floor, since raising or lowering --auto-scale-min is the next thing to try
+            let smallest = auto_scale_steps(config).last().copied().filter(|_| config.auto_scale || config.prefer != Prefer::Quality);
+            match smallest {
+                Some(scale) => format!(
+                    "Could not achieve target file size, even scaled to {:.0}% (--auto-scale-min {}%)",
+                    scale * 100.0,
+                    config.auto_scale_min
+                )
+                .into(),
+                None => "Could not achieve target file size".into(),
+            }
+        })
+}
+
+// Square images fit either orientation and are left as they are
+fn needs_rotation(img: &DynamicImage, config: &Config) -> bool {
+    match config.normalize_orientation {
+        Some(Orientation::Portrait) => img.width() > img.height(),
+        Some(Orientation::Landscape) => img.height() > img.width(),
+        None => false,
+    }
+}
+
+// Geometry shared by still images and every frame of an animation
+fn resize_for_config(mut img: DynamicImage, config: &Config) -> Result<DynamicImage, Box<dyn std::error::Error>> {
+    // Replaces every other resize option, so nothing can change the size afterwards
+    if let Some((width, height)) = config.equal_output_size {
+        return Ok(match config.equal_output_fit {
+            EqualFit::Pad => letterbox_image(&img, width, height, config.background, None, false, config.resampling),
+            EqualFit::Crop => crop_to_fill(&img, width, height, config.resampling),
+        });
+    }
+
+    // Apply dimension resize if specified
+    if let Some((width, height)) = config.dimensions {
+        if config.fail_on_upscale {
+            // Letterboxing always fits inside the box, so it only upscales like -r does
+            let maintain_ratio = config.maintain_aspect_ratio || config.letterbox;
+            let (new_width, new_height) =
+                compute_resize(img.width(), img.height(), width, height, maintain_ratio, config.round_dimensions, false);
+            if new_width > img.width() || new_height > img.height() {
+                return Err(format!(
+                    "Resizing {}x{} to {}x{} would upscale (--fail-on-upscale)",
+                    img.width(),
+                    img.height(),
+                    new_width,
+                    new_height
+                )
+                .into());
+            }
+        }
+
+        img = if config.letterbox {
+            letterbox_image(
+                &img,
+                width,
+                height,
+                config.background,
+                config.round_dimensions,
+                config.downscale_only,
+                config.resampling,
+            )
+        } else {
+            let resized = resize_image(
+                img,
+                width,
+                height,
+                config.maintain_aspect_ratio,
+                config.round_dimensions,
+                config.downscale_only,
+                config.resampling,
+            );
+            if config.verbose && config.maintain_aspect_ratio && resized.dimensions() != (width, height) {
+                log_verbose(format!("  → Aspect ratio kept: {}x{} instead of the requested {}x{}",
+                    resized.width(),
+                    resized.height(),
+                    width,
+                    height
+                ));
+            }
+            resized
+        };
+    }
+
+    if let Some(megapixels) = config.megapixels {
+        img = resize_to_megapixels(img, megapixels, config.round_dimensions, config.resampling);
+    }
+
+    if let Some(edge) = config.edge {
+        img = resize_to_edge(img, edge, config.allow_upscale, config.round_dimensions, config.resampling);
+    }
+
+    Ok(img)
+}
+
+// Stops after the second frame, so long animations are not decoded in full
+fn is_animated(path: &Path) -> bool {
+    let Ok(file) = fs::File::open(path) else {
+        return false;
+    };
+    let reader = std::io::BufReader::new(file);
+    match detect_format(path) {
+        Some(ImageFormat::Gif) => image::codecs::gif::GifDecoder::new(reader)
+            .map(|decoder| decoder.into_frames().take(2).count() > 1)
+            .unwrap_or(false),
+        Some(ImageFormat::WebP) => image::codecs::webp::WebPDecoder::new(reader)
+            .is_ok_and(|decoder| decoder.has_animation()),
+        _ => false,
+    }
+}
+
+fn decode_animation(input_path: &Path) -> Result<Vec<image::Frame>, Box<dyn std::error::Error>> {
+    let reader = std::io::BufReader::new(fs::File::open(input_path)?);
+    let frames = match detect_format(input_path) {
+        Some(ImageFormat::Gif) => image::codecs::gif::GifDecoder::new(reader)?.into_frames().collect_frames()?,
+        Some(ImageFormat::WebP) => {
+            let decoder = image::codecs::webp::WebPDecoder::new(reader)?;
+            if !decoder.has_animation() {
+                return Ok(Vec::new());
+            }
+            decoder.into_frames().collect_frames()?
+        }
+        _ => Vec::new(),
+    };
+    Ok(frames)
+}
+
+// Frames are decoded up to the one asked for by --frame; None for inputs that aren't GIF or WebP,
+// which decode as usual
+fn decode_frame(input_path: &Path, index: usize) -> Result<Option<DynamicImage>, Box<dyn std::error::Error>> {
+    let out_of_range = |count: usize| {
+        format!("{} has only {} frame(s), so there is no --frame {}", input_path.display(), count, index)
+    };
+    let reader = std::io::BufReader::new(fs::File::open(input_path)?);
+    let frames = match detect_format(input_path) {
+        Some(ImageFormat::Gif) => image::codecs::gif::GifDecoder::new(reader)?.into_frames(),
+        Some(ImageFormat::WebP) => {
+            let decoder = image::codecs::webp::WebPDecoder::new(reader)?;
+            // A still WebP is its own single frame
+            if !decoder.has_animation() {
+                return match index {
+                    0 => Ok(None),
+                    _ => Err(out_of_range(1).into()),
+                };
+            }
+            decoder.into_frames()
+        }
+        _ => return Ok(None),
+    };
+
+    let mut count = 0;
+    for frame in frames {
+        let frame = frame?;
+        if count == index {
+            return Ok(Some(DynamicImage::ImageRgba8(frame.into_buffer())));
+        }
+        count += 1;
+    }
+    Err(out_of_range(count).into())
+}
+
+fn render_animation(
+    frames: Vec<image::Frame>,
+    config: &Config,
+    timings: &mut StageTimings,
+) -> Result<CompressionResult, Box<dyn std::error::Error>> {
+    let frame_count = frames.len();
+
+    // Frames are full composited canvases, so each one takes the same geometry as a still image
+    let started = Instant::now();
+    let mut resized = Vec::with_capacity(frame_count);
+    let mut timestamp = 0;
+    let mut rotated = false;
+    let mut mismatch = None;
+    for frame in frames {
+        let (numer, denom) = frame.delay().numer_denom_ms();
+        let mut img = DynamicImage::ImageRgba8(frame.into_buffer());
+        // Every frame has the canvas size, so they all turn together
+        rotated = needs_rotation(&img, config);
+        if rotated {
+            img = img.rotate90();
+        }
+        mismatch = equal_size_mismatch(img.width(), img.height(), config);
+        let mut img = resize_for_config(img, config)?;
+        if let Some(palette) = &config.palette {
+            img = remap_to_palette(&img, palette, config.dither);
+        }
+        resized.push((img.to_rgba8(), timestamp));
+        timestamp += (numer / denom.max(1)) as i32;
+    }
+    timings.resize = started.elapsed();
+
+    let (width, height) = resized[0].0.dimensions();
+    let started = Instant::now();
+    let (data, quality) = match size_band(config) {
+        None => {
+            let quality = default_quality(ImageFormat::WebP, config);
+            (encode_animated_webp(&resized, timestamp, quality, config)?, quality)
+        }
+        Some((lower, upper)) => {
+            // Binary search for the highest quality that fits; frames are never scaled, so missing
+            // the target even at --min-quality fails like a still image would
+            let (mut low, mut high) = (config.min_quality, config.max_quality);
+            let mut best = None;
+            while low <= high {
+                let quality = low + (high - low) / 2;
+                let data = encode_animated_webp(&resized, timestamp, quality, config)?;
+                let size = data.len() as u64;
+                if size <= upper {
+                    best = Some((data, quality));
+                    if config.size_tolerance.is_some() && size >= lower {
+                        break;
+                    }
+                    low = quality + 1;
+                } else if quality == config.min_quality {
+                    break;
+                } else {
+                    high = quality - 1;
+                }
+            }
+            best.ok_or_else(|| {
+                format!("Could not achieve target file size for the animation, even at quality {}", config.min_quality)
+            })?
+        }
+    };
+    timings.encode = started.elapsed();
+
+    if config.verbose {
+        log_verbose(format!("  Animated WebP: {} frames, {}ms", frame_count, timestamp));
+    }
+
+    Ok(CompressionResult {
+        data,
+        quality,
+        scale: 1.0,
+        format: ImageFormat::WebP,
+        width,
+        height,
+        converted_from: None,
+        rotated,
+        mismatch,
+    })
+}
+
+fn encode_animated_webp(
+    frames: &[(image::RgbaImage, i32)],
+    end_timestamp: i32,
+    quality: u8,
+    config: &Config,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut webp_config = webp::WebPConfig::new().map_err(|_| "Failed to initialize WebP encoder")?;
+    webp_config.quality = quality as f32;
+    webp_config.method = config.webp_effort as i32;
+    webp_config.thread_level = webp_thread_level(config);
+    set_near_lossless(&mut webp_config, config);
+
+    let (width, height) = frames[0].0.dimensions();
+    let mut encoder = webp::AnimEncoder::new(width, height, &webp_config);
+    for (frame, timestamp) in frames {
+        encoder.add_frame(webp::AnimFrame::from_rgba(frame.as_raw(), width, height, *timestamp));
+    }
+    let encoded = encoder
+        .try_encode()
+        .map_err(|e| format!("Animated WebP encoding failed: {:?}", e))?;
+
+    let mut data = encoded.to_vec();
+    set_last_frame_duration(&mut data, end_timestamp);
+    Ok(data)
+}
+
+// The webp crate ends the animation at timestamp 0, so libwebp guesses the last frame's
+// duration; rewrite it from the real end time instead
+fn set_last_frame_duration(data: &mut [u8], end_timestamp: i32) {
+    let mut elapsed = 0;
+    let mut last_duration_at = None;
+    // Chunks follow the 12-byte RIFF/WEBP header, each padded to an even length
+    let mut offset = 12;
+    while offset + 8 <= data.len() {
+        let size = u32::from_le_bytes([data[offset + 4], data[offset + 5], data[offset + 6], data[offset + 7]]) as usize;
+        if &data[offset..offset + 4] == b"ANMF" && size >= 16 && offset + 8 + size <= data.len() {
+            let at = offset + 8 + 12;
+            if let Some(previous) = last_duration_at {
+                elapsed += read_u24(data, previous);
+            }
+            last_duration_at = Some(at);
+        }
+        offset += 8 + size + (size & 1);
+    }
+
+    if let Some(at) = last_duration_at {
+        let duration = (end_timestamp as u32).saturating_sub(elapsed).min(0xff_ffff);
+        data[at..at + 3].copy_from_slice(&duration.to_le_bytes()[..3]);
+    }
+}
+
+fn read_u24(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([data[at], data[at + 1], data[at + 2], 0])
+}
+
+fn sidecar_path(input_path: &Path) -> PathBuf {
+    let mut path = input_path.as_os_str().to_owned();
+    path.push(SIDECAR_SUFFIX);
+    PathBuf::from(path)
+}
+
+fn load_sidecar(input_path: &Path) -> Result<Option<Sidecar>, Box<dyn std::error::Error>> {
+    let path = sidecar_path(input_path);
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e).into()),
+    };
+    toml::from_str(&contents)
+        .map(Some)
+        .map_err(|e| format!("Invalid {}: {}", path.display(), e).into())
+}
+
+// The batch settings with the image's sidecar merged in, or None when it has no sidecar
+fn image_config(input_path: &Path, config: &Config) -> Result<Option<Config>, Box<dyn std::error::Error>> {
+    load_sidecar(input_path)?.map(|sidecar| apply_sidecar(sidecar, config)).transpose()
+}
+
+fn apply_sidecar(sidecar: Sidecar, config: &Config) -> Result<Config, Box<dyn std::error::Error>> {
+    let mut merged = config.clone();
+    if let Some(size) = sidecar.size {
+        merged.target_size_kb = Some(size);
+    }
+    if let Some(dimensions) = sidecar.dimensions {
+        merged.dimensions = Some(
+            parse_dimensions(Some(&dimensions), config.dpi)?
+                .ok_or_else(|| format!("invalid dimensions '{}' in sidecar, expected WIDTHxHEIGHT", dimensions))?,
+        );
+        // Explicit dimensions for this image replace a batch-wide pixel budget or edge length
+        merged.megapixels = None;
+        merged.edge = None;
+    }
+    if let Some(maintain_ratio) = sidecar.maintain_ratio {
+        merged.maintain_aspect_ratio = maintain_ratio;
+    }
+    if let Some(auto_scale) = sidecar.auto_scale {
+        merged.auto_scale = auto_scale;
+    }
+    for quality in [sidecar.min_quality, sidecar.max_quality].into_iter().flatten() {
+        if !(1..=100).contains(&quality) {
+            return Err(format!("quality {} in sidecar is out of range 1-100", quality).into());
+        }
+    }
+    if let Some(curve) = sidecar.quality_curve {
+        merged.quality_curve = Some(parse_quality_curve(&curve).map_err(|e| format!("{} in sidecar", e))?);
+    }
+    merged.min_quality = sidecar.min_quality.unwrap_or(merged.min_quality);
+    merged.max_quality = sidecar.max_quality.unwrap_or(merged.max_quality);
+    if merged.min_quality > merged.max_quality {
+        return Err("min-quality cannot be greater than max-quality after applying sidecar".into());
+    }
+    Ok(merged)
+}
+
+struct CompressionResult {
+    data: Vec<u8>,
+    quality: u8,
+    scale: f32,
+    format: ImageFormat,
+    width: u32,
+    height: u32,
+    // Format the size target was out of reach in, when --force-format-on-failure switched formats
+    converted_from: Option<ImageFormat>,
+    // Turned a quarter by --normalize-orientation
+    rotated: bool,
+    // Padded or cropped share under --equal-output-size, see equal_size_mismatch
+    mismatch: Option<f64>,
+}
+
+// None when the target can't be reached in this format
+fn smart_compress(
+    img: &DynamicImage,
+    target_bytes: u64,
+    format: ImageFormat,
+    config: &Config,
+) -> Result<Option<CompressionResult>, Box<dyn std::error::Error>> {
+    match config.prefer {
+        Prefer::Scale => return scale_first(img, target_bytes, format, config),
+        Prefer::Balanced => return balanced_search(img, target_bytes, format, config),
+        Prefer::Quality => {}
+    }
+
+    let verbose = config.verbose;
+
+    // First, try to achieve target with quality adjustment only
+    let mut best_result = search_quality(img, target_bytes, format, 1.0, config)?;
+
+    // Unless pinned, coarser chroma subsampling is a cheaper lever than scaling
+    let relaxed_config;
+    let mut config = config;
+    if best_result.is_none() && format == ImageFormat::Jpeg && config.subsampling.is_none() {
+        if verbose {
+            log_verbose("  Retrying with 4:2:0 chroma subsampling".to_string());
+        }
+        relaxed_config = Config {
+            subsampling: Some(Subsampling::S420),
+            ..config.clone()
+        };
+        config = &relaxed_config;
+        best_result = search_quality(img, target_bytes, format, 1.0, config)?;
+    }
+    
+    // If quality adjustment alone isn't enough, start scaling
+    if best_result.is_none() && config.auto_scale {
+        for scale_factor in auto_scale_steps(config) {
+            let scaled_img = scale_image(img, scale_factor, config.resampling);
+            best_result = search_quality(&scaled_img, target_bytes, format, scale_factor, config)?;
+            if best_result.is_some() {
+                break;
+            }
+        }
+    }
+    
+    Ok(best_result)
+}
+
+// Scale factors tried by --auto-scale, largest first, down to --auto-scale-min
+fn auto_scale_steps(config: &Config) -> Vec<f32> {
+    let floor = f32::from(config.auto_scale_min) / 100.0;
+    let mut steps = Vec::new();
+    let mut scale_factor = config.auto_scale_start;
+    while scale_factor >= floor {
+        steps.push(scale_factor);
+        scale_factor *= config.auto_scale_step;
     }
+    steps
 }
 
-fn collect_images(path: &Path) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
-    let mut images = Vec::new();
+// Shrink at --max-quality first and only search lower qualities at the smallest scale
+fn scale_first(
+    img: &DynamicImage,
+    target_bytes: u64,
+    format: ImageFormat,
+    config: &Config,
+) -> Result<Option<CompressionResult>, Box<dyn std::error::Error>> {
+    let steps = auto_scale_steps(config);
+    let mut smallest = None;
 
-    if path.is_file() {
-        if is_image_file(path) {
-            images.push(path.to_path_buf());
+    for scale_factor in std::iter::once(1.0).chain(steps.iter().copied()) {
+        let scaled_img = if scale_factor < 1.0 { scale_image(img, scale_factor, config.resampling) } else { img.clone() };
+        let buffer = save_to_buffer(&scaled_img, format, config.max_quality, config)?;
+        let size = buffer.len() as u64;
+
+        if config.verbose {
+            log_verbose(format!("  Testing scale {:.0}%, quality {}: {} KB",
+                scale_factor * 100.0, config.max_quality, size / 1024));
         }
-    } else if path.is_dir() {
-        for entry in WalkDir::new(path).into_iter().filter_map(|e| e.ok()) {
-            let path = entry.path();
-            if path.is_file() && is_image_file(path) {
-                images.push(path.to_path_buf());
-            }
+
+        if size <= target_bytes {
+            return Ok(Some(CompressionResult {
+                data: buffer,
+                quality: config.max_quality,
+                scale: scale_factor,
+                format,
+                width: scaled_img.width(),
+                height: scaled_img.height(),
+                converted_from: None,
+                rotated: false,
+                mismatch: None,
+            }));
         }
+        smallest = Some((scaled_img, scale_factor));
     }
 
-    Ok(images)
+    match smallest {
+        Some((scaled_img, scale_factor)) => search_quality(&scaled_img, target_bytes, format, scale_factor, config),
+        None => Ok(None),
+    }
 }
 
-fn is_image_file(path: &Path) -> bool {
-    match path.extension() {
-        Some(ext) => {
-            let ext = ext.to_string_lossy().to_lowercase();
-            matches!(ext.as_str(), "jpg" | "jpeg" | "png" | "gif" | "bmp" | "webp" | "tiff" | "tif")
+// Walk down the scales and stop at the first one that fits at a mid-range quality or better
+fn balanced_search(
+    img: &DynamicImage,
+    target_bytes: u64,
+    format: ImageFormat,
+    config: &Config,
+) -> Result<Option<CompressionResult>, Box<dyn std::error::Error>> {
+    let threshold = config.min_quality + (config.max_quality - config.min_quality) / 2;
+    let mut fallback = None;
+
+    for scale_factor in std::iter::once(1.0).chain(auto_scale_steps(config)) {
+        let scaled_img = if scale_factor < 1.0 { scale_image(img, scale_factor, config.resampling) } else { img.clone() };
+        if let Some(result) = search_quality(&scaled_img, target_bytes, format, scale_factor, config)? {
+            if result.quality >= threshold {
+                return Ok(Some(result));
+            }
+            // Keep the largest scale that fit at all in case no scale reaches the threshold
+            fallback.get_or_insert(result);
         }
-        None => false,
     }
+
+    Ok(fallback)
 }
 
-fn process_single_image(
-    input_path: &Path,
+// (smallest accepted, largest allowed) bytes for the size target; both are the target itself
+// unless --size-tolerance widens them
+fn size_band(config: &Config) -> Option<(u64, u64)> {
+    let target = (config.target_size_kb? * 1024) as f64;
+    let tolerance = config.size_tolerance.unwrap_or(0.0) / 100.0;
+    Some(((target * (1.0 - tolerance)) as u64, (target * (1.0 + tolerance)) as u64))
+}
+
+fn search_quality(
+    img: &DynamicImage,
+    target_bytes: u64,
+    format: ImageFormat,
+    scale: f32,
     config: &Config,
-) -> Result<PathBuf, Box<dyn std::error::Error>> {
-    let mut img = image::open(input_path)?;
-    
-    // Apply dimension resize if specified
-    if let Some((width, height)) = config.dimensions {
-        img = resize_image(img, width, height, config.maintain_aspect_ratio);
+) -> Result<Option<CompressionResult>, Box<dyn std::error::Error>> {
+    // Binary search for optimal quality
+    let mut low_quality = config.min_quality;
+    let mut high_quality = config.max_quality;
+    let mut best_result = None;
+    // Close enough to the target that a better quality isn't worth more encodes
+    let good_enough = config.size_tolerance.and(size_band(config)).map(|(lower, _)| lower);
+    let fitted = |buffer: Vec<u8>, quality: u8| CompressionResult {
+        data: buffer,
+        quality,
+        scale,
+        format,
+        width: img.width(),
+        height: img.height(),
+        converted_from: None,
+        rotated: false,
+        mismatch: None,
+    };
+
+    // QOI has no quality setting, so a single encode decides whether this scale fits
+    #[cfg(feature = "qoi")]
+    if format == ImageFormat::Qoi {
+        let buffer = encode_at_quality(img, format, config.max_quality, scale, config)?;
+        return Ok((buffer.len() as u64 <= target_bytes).then(|| fitted(buffer, config.max_quality)));
     }
 
-    // Determine output path
-    let output_path = get_output_path(input_path, config)?;
-    
-    // If no target size specified, just save with default quality
-    if config.target_size_kb.is_none() {
-        save_image(&img, &output_path, 90)?;
-        return Ok(output_path);
+    let model = match config.sample_pixels {
+        true => sample_model(img, format, config)?,
+        false => None,
+    };
+    if let Some(model) = &model {
+        // Walk away from the estimate in growing steps until one quality fits and the next doesn't;
+        // the binary search below then only covers the gap, and still ends on a full-size encode.
+        // The proxy's sizes are off by a roughly constant factor for a given image, so the first
+        // full-size encode measures it and the walk starts over from the corrected estimate
+        let mut quality = estimate_quality(model, target_bytes, format, config)?;
+        if config.verbose {
+            log_verbose(format!("  Sampled quality estimate: {}", quality));
+        }
+        let mut calibrated = false;
+        let mut step = SAMPLE_STEP;
+        let mut rising = None;
+        while low_quality <= high_quality {
+            let buffer = encode_at_quality(img, format, quality, scale, config)?;
+            let size = buffer.len() as u64;
+            let fits = size <= target_bytes;
+            if fits {
+                best_result = Some(fitted(buffer, quality));
+                if good_enough.is_some_and(|lower| size >= lower) {
+                    return Ok(best_result);
+                }
+                low_quality = quality + 1;
+            } else {
+                high_quality = quality - 1;
+            }
+            if !calibrated {
+                calibrated = true;
+                let predicted = predicted_size(model, quality, format, config)?;
+                let corrected = (target_bytes as f64 * predicted as f64 / size.max(1) as f64) as u64;
+                let estimate = estimate_quality(model, corrected, format, config)?;
+                if config.verbose {
+                    log_verbose(format!("  Calibrated quality estimate: {}", estimate));
+                }
+                if estimate != quality && low_quality <= high_quality {
+                    quality = estimate.clamp(low_quality, high_quality);
+                    continue;
+                }
+            }
+            if rising.is_some_and(|rising| rising != fits) {
+                break;
+            }
+            rising = Some(fits);
+            quality = if fits {
+                quality.saturating_add(step).min(high_quality)
+            } else {
+                quality.saturating_sub(step).max(low_quality)
+            };
+            step = step.saturating_mul(2);
+        }
     }
 
-    // Apply file size reduction using smart algorithm
-    let target_bytes = config.target_size_kb.unwrap() * 1024;
-    let format = get_image_format(input_path)?;
-    
-    // Smart compression algorithm
-    let result = smart_compress(img, target_bytes, format, config.verbose, config.auto_scale)?;
-    
-    // Save the result
-    fs::write(&output_path, result.data)?;
-    
-    if config.verbose {
-        println!("  → Final quality: {}, Scale: {:.0}%", 
-            result.quality, 
-            result.scale * 100.0
-        );
+    while low_quality <= high_quality {
+        let quality = (low_quality + high_quality) / 2;
+        let buffer = encode_at_quality(img, format, quality, scale, config)?;
+        let size = buffer.len() as u64;
+        
+        if size <= target_bytes {
+            best_result = Some(fitted(buffer, quality));
+            if good_enough.is_some_and(|lower| size >= lower) {
+                break;
+            }
+            low_quality = quality + 1;
+        } else {
+            high_quality = quality - 1;
+        }
+    }
+
+    if config.two_pass {
+        if let Some(best) = best_result.take() {
+            best_result = Some(refine_quality(img, best, target_bytes, config)?);
+        }
     }
 
-    Ok(output_path)
+    Ok(best_result)
 }
 
-struct CompressionResult {
-    data: Vec<u8>,
+// Full-resolution encodes made by the size searches on this thread, counted for the tests
+#[cfg(test)]
+thread_local! {
+    static FULL_ENCODES: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+fn encode_at_quality(
+    img: &DynamicImage,
+    format: ImageFormat,
     quality: u8,
     scale: f32,
+    config: &Config,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let buffer = save_to_buffer(img, format, quality, config)?;
+    #[cfg(test)]
+    FULL_ENCODES.with(|count| count.set(count.get() + 1));
+    if config.verbose {
+        let size = buffer.len() / 1024;
+        if scale < 1.0 {
+            log_verbose(format!("  Testing scale {:.0}%, quality {}: {} KB", scale * 100.0, quality, size));
+        } else {
+            log_verbose(format!("  Testing quality {}: {} KB", quality, size));
+        }
+    }
+    Ok(buffer)
 }
 
-fn smart_compress(
-    img: DynamicImage,
+// A proxy built from tiles spread over the image, a quarter of its pixels, searched in place of
+// the image against a target shrunk by the same ratio. Tiles keep the original detail per pixel,
+// which a downscaled copy would not
+struct SampleModel {
+    proxy: DynamicImage,
+    // Proxy pixels per image pixel
+    ratio: f64,
+    // Headers and tables, which don't shrink with the pixel count
+    overhead: u64,
+}
+
+// None for images too small to be worth it
+fn sample_model(
+    img: &DynamicImage,
+    format: ImageFormat,
+    config: &Config,
+) -> Result<Option<SampleModel>, Box<dyn std::error::Error>> {
+    if img.width().min(img.height()) < SAMPLE_MIN_SIDE {
+        return Ok(None);
+    }
+    let proxy = sample_tiles(img);
+    let ratio = (proxy.width() as f64 * proxy.height() as f64) / (img.width() as f64 * img.height() as f64);
+    let overhead = save_to_buffer(&img.crop_imm(0, 0, 1, 1), format, config.min_quality, config)?.len() as u64;
+    Ok(Some(SampleModel { proxy, ratio, overhead }))
+}
+
+// Highest quality whose proxy encode fits the proxy's share of the target
+fn estimate_quality(
+    model: &SampleModel,
     target_bytes: u64,
     format: ImageFormat,
-    verbose: bool,
-	auto_scale: bool
-) -> Result<CompressionResult, Box<dyn std::error::Error>> {
-    // Binary search for optimal quality
-    let mut low_quality = 10;
-    let mut high_quality = 95;
-    let mut best_result = None;
-    let mut scale_factor = 1.0;
-    
-    // First, try to achieve target with quality adjustment only
+    config: &Config,
+) -> Result<u8, Box<dyn std::error::Error>> {
+    let proxy_target = model.overhead + (target_bytes.saturating_sub(model.overhead) as f64 * model.ratio) as u64;
+
+    let mut low_quality = config.min_quality;
+    let mut high_quality = config.max_quality;
+    let mut estimate = config.min_quality;
     while low_quality <= high_quality {
         let quality = (low_quality + high_quality) / 2;
-        let buffer = save_to_buffer(&img, format, quality)?;
-        let size = buffer.len() as u64;
-        
-        if verbose {
-            println!("  Testing quality {}: {} KB", quality, size / 1024);
-        }
-        
-        if size <= target_bytes {
-            best_result = Some(CompressionResult {
-                data: buffer,
-                quality,
-                scale: 1.0,
-            });
+        if save_to_buffer(&model.proxy, format, quality, config)?.len() as u64 <= proxy_target {
+            estimate = quality;
             low_quality = quality + 1;
         } else {
             high_quality = quality - 1;
         }
     }
-    
-    // If quality adjustment alone isn't enough, start scaling
-    if best_result.is_none() && auto_scale {
-        scale_factor = 0.95;
-        
-        while scale_factor > 0.3 {
-            let scaled_img = scale_image(&img, scale_factor);
-            
-            // Binary search with scaled image
-            low_quality = 60;
-            high_quality = 95;
-            
-            while low_quality <= high_quality {
-                let quality = (low_quality + high_quality) / 2;
-                let buffer = save_to_buffer(&scaled_img, format, quality)?;
-                let size = buffer.len() as u64;
-                
-                if verbose {
-                    println!("  Testing scale {:.0}%, quality {}: {} KB", 
-                        scale_factor * 100.0, quality, size / 1024);
-                }
-                
-                if size <= target_bytes {
-                    best_result = Some(CompressionResult {
-                        data: buffer,
-                        quality,
-                        scale: scale_factor,
-                    });
-                    break;
-                } else {
-                    high_quality = quality - 1;
+    Ok(estimate)
+}
+
+// The full-size encode the proxy predicts at this quality
+fn predicted_size(
+    model: &SampleModel,
+    quality: u8,
+    format: ImageFormat,
+    config: &Config,
+) -> Result<u64, Box<dyn std::error::Error>> {
+    let proxy_size = save_to_buffer(&model.proxy, format, quality, config)?.len() as u64;
+    Ok(model.overhead + (proxy_size.saturating_sub(model.overhead) as f64 / model.ratio) as u64)
+}
+
+// Half of each cell of a SAMPLE_GRID x SAMPLE_GRID grid, taken from the cell's center and
+// aligned to JPEG's 16 pixel blocks so the seams fall on block edges
+fn sample_tiles(img: &DynamicImage) -> DynamicImage {
+    let tile_width = (img.width() / SAMPLE_GRID / 2 / 16 * 16).max(16);
+    let tile_height = (img.height() / SAMPLE_GRID / 2 / 16 * 16).max(16);
+    let mut proxy = DynamicImage::new(tile_width * SAMPLE_GRID, tile_height * SAMPLE_GRID, img.color());
+    for row in 0..SAMPLE_GRID {
+        for column in 0..SAMPLE_GRID {
+            let x = column * img.width() / SAMPLE_GRID + (img.width() / SAMPLE_GRID - tile_width) / 2;
+            let y = row * img.height() / SAMPLE_GRID + (img.height() / SAMPLE_GRID - tile_height) / 2;
+            let tile = img.crop_imm(x, y, tile_width, tile_height);
+            image::imageops::replace(&mut proxy, &tile, i64::from(column * tile_width), i64::from(row * tile_height));
+        }
+    }
+    proxy
+}
+
+fn refine_quality(
+    img: &DynamicImage,
+    candidate: CompressionResult,
+    target_bytes: u64,
+    config: &Config,
+) -> Result<CompressionResult, Box<dyn std::error::Error>> {
+    // Quality is ignored by lossless encoders, so there is nothing to refine
+    if !matches!(candidate.format, ImageFormat::Jpeg | ImageFormat::WebP) {
+        return Ok(candidate);
+    }
+
+    // Size isn't strictly monotonic in quality, so a neighbor can land closer to the target
+    let low = candidate.quality.saturating_sub(TWO_PASS_RADIUS).max(config.min_quality);
+    let high = candidate.quality.saturating_add(TWO_PASS_RADIUS).min(config.max_quality);
+    let searched = candidate.quality;
+    let mut best = candidate;
+    let mut passes = 0;
+
+    for quality in low..=high {
+        if quality == searched {
+            continue;
+        }
+        let buffer = save_to_buffer(img, best.format, quality, config)?;
+        let size = buffer.len() as u64;
+        passes += 1;
+
+        if size <= target_bytes && size > best.data.len() as u64 {
+            best = CompressionResult { data: buffer, quality, ..best };
+        }
+    }
+
+    if config.verbose {
+        log_verbose(format!("  Two-pass: {} extra encode(s), quality {} → {}", passes, searched, best.quality));
+    }
+
+    Ok(best)
+}
+
+fn read_icc_profile(path: &Path) -> Option<Vec<u8>> {
+    use image::ImageDecoder;
+
+    let reader = std::io::BufReader::new(fs::File::open(path).ok()?);
+    match input_format(path)? {
+        ImageFormat::Jpeg => image::codecs::jpeg::JpegDecoder::new(reader).ok()?.icc_profile(),
+        ImageFormat::Png => image::codecs::png::PngDecoder::new(reader).ok()?.icc_profile(),
+        ImageFormat::WebP => image::codecs::webp::WebPDecoder::new(reader).ok()?.icc_profile(),
+        _ => None,
+    }
+}
+
+fn convert_to_srgb(img: DynamicImage, icc: &[u8]) -> Result<DynamicImage, Box<dyn std::error::Error>> {
+    use lcms2::{Intent, PixelFormat, Profile, Transform};
+
+    let source = Profile::new_icc(icc)?;
+    // Only RGB profiles map onto the decoded RGB pixels
+    if source.color_space() != lcms2::ColorSpaceSignature::RgbData {
+        return Ok(img);
+    }
+    let srgb = Profile::new_srgb();
+
+    if img.color().has_alpha() {
+        let mut pixels = img.to_rgba8();
+        let transform = Transform::new(&source, PixelFormat::RGBA_8, &srgb, PixelFormat::RGBA_8, Intent::Perceptual)?;
+        transform.transform_in_place(pixels.as_mut().as_chunks_mut::<4>().0);
+        Ok(DynamicImage::ImageRgba8(pixels))
+    } else {
+        let mut pixels = img.to_rgb8();
+        let transform = Transform::new(&source, PixelFormat::RGB_8, &srgb, PixelFormat::RGB_8, Intent::Perceptual)?;
+        transform.transform_in_place(pixels.as_mut().as_chunks_mut::<3>().0);
+        Ok(DynamicImage::ImageRgb8(pixels))
+    }
+}
+
+fn scale_image(img: &DynamicImage, scale: f32, resampling: Resampling) -> DynamicImage {
+    let new_width = (img.width() as f32 * scale) as u32;
+    let new_height = (img.height() as f32 * scale) as u32;
+
+    // Fit inside the scaled box with the same rounding as DynamicImage::resize
+    let ratio = f64::min(new_width as f64 / img.width() as f64, new_height as f64 / img.height() as f64);
+    let width = ((img.width() as f64 * ratio).round() as u32).max(1);
+    let height = ((img.height() as f64 * ratio).round() as u32).max(1);
+    if (width, height) == img.dimensions() {
+        return img.clone();
+    }
+    resample(img, width, height, resampling)
+}
+
+// The GPU shader is Lanczos3 only, so other windows always resample on the CPU
+fn resample(img: &DynamicImage, width: u32, height: u32, resampling: Resampling) -> DynamicImage {
+    let filter_is_lanczos3 = resampling.lanczos_window == 3
+        && !(resampling.fast_thumbnails && width.max(height) <= FAST_THUMBNAIL_MAX_SIDE);
+    filter_is_lanczos3
+        .then(|| gpu_resize(img, width, height))
+        .flatten()
+        .unwrap_or_else(|| cpu_resample(img, width, height, resampling))
+}
+
+// Lanczos3 everywhere, except that --fast-thumbnails trades it for Triangle on outputs too small
+// for the difference to show, and --lanczos-window picks a window image has no filter for
+fn cpu_resample(img: &DynamicImage, width: u32, height: u32, resampling: Resampling) -> DynamicImage {
+    use image::imageops::FilterType;
+
+    if resampling.fast_thumbnails && width.max(height) <= FAST_THUMBNAIL_MAX_SIDE {
+        img.resize_exact(width, height, FilterType::Triangle)
+    } else if resampling.lanczos_window == 3 {
+        img.resize_exact(width, height, FilterType::Lanczos3)
+    } else {
+        lanczos_resize(img, width, height, resampling.lanczos_window)
+    }
+}
+
+// Separable Lanczos with any window, one horizontal and one vertical pass in floating point.
+// Like image's own filters, the kernel is widened when downscaling so every source pixel counts
+fn lanczos_resize(img: &DynamicImage, width: u32, height: u32, window: u32) -> DynamicImage {
+    let source = img.to_rgba32f();
+    let (src_width, src_height) = source.dimensions();
+
+    let columns = lanczos_weights(src_width, width, window);
+    let mut horizontal = image::Rgba32FImage::new(width, src_height);
+    for y in 0..src_height {
+        for (x, (first, weights)) in columns.iter().enumerate() {
+            let mut sum = [0.0f32; 4];
+            for (offset, weight) in weights.iter().enumerate() {
+                let pixel = source.get_pixel((first + offset) as u32, y);
+                for (total, channel) in sum.iter_mut().zip(pixel.0) {
+                    *total += channel * weight;
                 }
             }
-            
-            if best_result.is_some() {
-                break;
+            horizontal.put_pixel(x as u32, y, image::Rgba(sum));
+        }
+    }
+
+    let rows = lanczos_weights(src_height, height, window);
+    let mut resized = image::Rgba32FImage::new(width, height);
+    for (y, (first, weights)) in rows.iter().enumerate() {
+        for x in 0..width {
+            let mut sum = [0.0f32; 4];
+            for (offset, weight) in weights.iter().enumerate() {
+                let pixel = horizontal.get_pixel(x, (first + offset) as u32);
+                for (total, channel) in sum.iter_mut().zip(pixel.0) {
+                    *total += channel * weight;
+                }
             }
-            
-            scale_factor *= 0.85;
+            resized.put_pixel(x, y as u32, image::Rgba(sum.map(|channel| channel.clamp(0.0, 1.0))));
         }
     }
-    
-    best_result.ok_or_else(|| "Could not achieve target file size".into())
+
+    // Back to the source's color type so encoders see the same channels as with image's filters
+    let resized = DynamicImage::ImageRgba32F(resized);
+    match img {
+        DynamicImage::ImageLuma8(_) => DynamicImage::ImageLuma8(resized.to_luma8()),
+        DynamicImage::ImageLumaA8(_) => DynamicImage::ImageLumaA8(resized.to_luma_alpha8()),
+        DynamicImage::ImageRgb8(_) => DynamicImage::ImageRgb8(resized.to_rgb8()),
+        DynamicImage::ImageRgba8(_) => DynamicImage::ImageRgba8(resized.to_rgba8()),
+        DynamicImage::ImageLuma16(_) => DynamicImage::ImageLuma16(resized.to_luma16()),
+        DynamicImage::ImageLumaA16(_) => DynamicImage::ImageLumaA16(resized.to_luma_alpha16()),
+        DynamicImage::ImageRgb16(_) => DynamicImage::ImageRgb16(resized.to_rgb16()),
+        DynamicImage::ImageRgba16(_) => DynamicImage::ImageRgba16(resized.to_rgba16()),
+        DynamicImage::ImageRgb32F(_) => DynamicImage::ImageRgb32F(resized.to_rgb32f()),
+        _ => resized,
+    }
+}
+
+// For each output position along one axis: the first source pixel it reads and the normalized
+// weights of it and its neighbours
+fn lanczos_weights(src_len: u32, dst_len: u32, window: u32) -> Vec<(usize, Vec<f32>)> {
+    let ratio = src_len as f32 / dst_len as f32;
+    let scale = ratio.max(1.0);
+    let support = window as f32 * scale;
+
+    (0..dst_len)
+        .map(|i| {
+            let center = (i as f32 + 0.5) * ratio;
+            let first = (center - support).floor().max(0.0) as usize;
+            let last = ((center + support).ceil() as usize).min(src_len as usize);
+            let mut weights: Vec<f32> = (first..last)
+                .map(|j| lanczos_kernel((j as f32 + 0.5 - center) / scale, window as f32))
+                .collect();
+            let total: f32 = weights.iter().sum();
+            if total != 0.0 {
+                weights.iter_mut().for_each(|weight| *weight /= total);
+            }
+            (first, weights)
+        })
+        .collect()
+}
+
+fn lanczos_kernel(x: f32, window: f32) -> f32 {
+    let sinc = |x: f32| if x == 0.0 { 1.0 } else { (x * std::f32::consts::PI).sin() / (x * std::f32::consts::PI) };
+    if x.abs() >= window {
+        0.0
+    } else {
+        sinc(x) * sinc(x / window)
+    }
+}
+
+// Resample on the GPU when --gpu found an adapter; None means the caller resizes on the CPU
+#[cfg(feature = "gpu")]
+fn gpu_resize(img: &DynamicImage, width: u32, height: u32) -> Option<DynamicImage> {
+    gpu::resize(img, width, height)
+}
+
+#[cfg(not(feature = "gpu"))]
+fn gpu_resize(_img: &DynamicImage, _width: u32, _height: u32) -> Option<DynamicImage> {
+    None
+}
+
+fn encode_ico(
+    img: &DynamicImage,
+    sizes: &[u32],
+    maintain_ratio: bool,
+    downscale_only: bool,
+    resampling: Resampling,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    use image::codecs::ico::{IcoEncoder, IcoFrame};
+
+    let mut icons = Vec::with_capacity(sizes.len());
+    for &size in sizes {
+        // Anything that doesn't fill the square is centered on a transparent canvas
+        let (width, height) = compute_resize(img.width(), img.height(), size, size, maintain_ratio, None, downscale_only);
+        let fitted = cpu_resample(img, width, height, resampling).to_rgba8();
+        let mut canvas = image::RgbaImage::new(size, size);
+        let x = (size - width) / 2;
+        let y = (size - height) / 2;
+        image::imageops::overlay(&mut canvas, &fitted, x as i64, y as i64);
+        icons.push(canvas);
+    }
+
+    let frames = icons
+        .iter()
+        .map(|icon| IcoFrame::as_png(icon.as_raw(), icon.width(), icon.height(), image::ColorType::Rgba8))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut buffer = Vec::new();
+    IcoEncoder::new(&mut buffer).encode_images(&frames)?;
+    Ok(buffer)
+}
+
+fn resize_image(
+    img: DynamicImage,
+    width: u32,
+    height: u32,
+    maintain_ratio: bool,
+    round_to: Option<u32>,
+    downscale_only: bool,
+    resampling: Resampling,
+) -> DynamicImage {
+    let (width, height) = compute_resize(img.width(), img.height(), width, height, maintain_ratio, round_to, downscale_only);
+    if (width, height) == img.dimensions() {
+        return img;
+    }
+    resample(&img, width, height, resampling)
+}
+
+// Scan inward from each edge while whole rows or columns match the top-left color; returns the
+// (x, y, width, height) to keep, or None when there is no border or the image is one flat color
+fn find_trim(img: &DynamicImage, tolerance: u8) -> Option<(u32, u32, u32, u32)> {
+    let pixels = img.to_rgba8();
+    let (width, height) = pixels.dimensions();
+    let border = *pixels.get_pixel(0, 0);
+    let matches = |x: u32, y: u32| {
+        let pixel = pixels.get_pixel(x, y);
+        (0..4).all(|c| pixel[c].abs_diff(border[c]) <= tolerance)
+    };
+    let row_is_border = |y: u32| (0..width).all(|x| matches(x, y));
+
+    let top = (0..height).find(|&y| !row_is_border(y))?;
+    let bottom = (top..height).rev().find(|&y| !row_is_border(y))?;
+    let column_is_border = |x: u32| (top..=bottom).all(|y| matches(x, y));
+    let left = (0..width).find(|&x| !column_is_border(x))?;
+    let right = (left..width).rev().find(|&x| !column_is_border(x))?;
+
+    let trimmed = (left, top, right - left + 1, bottom - top + 1);
+    (trimmed != (0, 0, width, height)).then_some(trimmed)
+}
+
+impl image::imageops::colorops::ColorMap for Palette {
+    type Color = image::Rgba<u8>;
+
+    fn index_of(&self, color: &Self::Color) -> usize {
+        // Plain squared RGB distance; alpha is left to the encoder
+        let distance = |entry: &[u8; 3]| -> u32 {
+            (0..3).map(|c| (entry[c] as i32 - color[c] as i32).pow(2) as u32).sum()
+        };
+        (0..self.colors.len()).min_by_key(|&i| distance(&self.colors[i])).unwrap_or(0)
+    }
+
+    fn lookup(&self, index: usize) -> Option<Self::Color> {
+        self.colors.get(index).map(|&[r, g, b]| image::Rgba([r, g, b, 255]))
+    }
+
+    fn has_lookup(&self) -> bool {
+        true
+    }
+
+    fn map_color(&self, color: &mut Self::Color) {
+        let [r, g, b] = self.colors[self.index_of(color)];
+        *color = image::Rgba([r, g, b, color[3]]);
+    }
+}
+
+fn remap_to_palette(img: &DynamicImage, palette: &Palette, dither: bool) -> DynamicImage {
+    use image::imageops::colorops::ColorMap;
+
+    let mut pixels = img.to_rgba8();
+    if dither {
+        image::imageops::dither(&mut pixels, palette);
+    } else {
+        pixels.pixels_mut().for_each(|pixel| palette.map_color(pixel));
+    }
+
+    if img.color().has_alpha() {
+        DynamicImage::ImageRgba8(pixels)
+    } else {
+        DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(pixels).to_rgb8())
+    }
+}
+
+// Blend any transparency over an opaque background color
+fn flatten_onto(img: &DynamicImage, background: image::Rgba<u8>) -> image::RgbImage {
+    if !img.color().has_alpha() {
+        return img.to_rgb8();
+    }
+    let [r, g, b, _] = background.0;
+    let mut canvas = image::RgbaImage::from_pixel(img.width(), img.height(), image::Rgba([r, g, b, 255]));
+    image::imageops::overlay(&mut canvas, &img.to_rgba8(), 0, 0);
+    DynamicImage::ImageRgba8(canvas).to_rgb8()
+}
+
+// Fit inside the box and center it on a solid canvas of exactly width x height
+fn letterbox_image(
+    img: &DynamicImage,
+    width: u32,
+    height: u32,
+    background: image::Rgba<u8>,
+    round_to: Option<u32>,
+    downscale_only: bool,
+    resampling: Resampling,
+) -> DynamicImage {
+    // With --downscale-only a small image sits at its own size in the middle of the padded box
+    let (fit_width, fit_height) = compute_resize(img.width(), img.height(), width, height, true, round_to, downscale_only);
+    let fitted = resample(img, fit_width, fit_height, resampling).to_rgba8();
+
+    let mut canvas = image::RgbaImage::from_pixel(width, height, background);
+    let x = (width - fit_width) / 2;
+    let y = (height - fit_height) / 2;
+    image::imageops::overlay(&mut canvas, &fitted, x as i64, y as i64);
+
+    // Opaque padding around an opaque image doesn't need an alpha channel
+    if background[3] == 255 && !img.color().has_alpha() {
+        DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(canvas).to_rgb8())
+    } else {
+        DynamicImage::ImageRgba8(canvas)
+    }
 }
 
-fn scale_image(img: &DynamicImage, scale: f32) -> DynamicImage {
-    let new_width = (img.width() as f32 * scale) as u32;
-    let new_height = (img.height() as f32 * scale) as u32;
-    img.resize(new_width, new_height, image::imageops::FilterType::Lanczos3)
+// Scale until the box is covered, then keep its center
+fn crop_to_fill(img: &DynamicImage, width: u32, height: u32, resampling: Resampling) -> DynamicImage {
+    let (fill_width, fill_height) = fill_dimensions(img.width(), img.height(), width, height);
+    let filled = if (fill_width, fill_height) == img.dimensions() {
+        img.clone()
+    } else {
+        resample(img, fill_width, fill_height, resampling)
+    };
+    filled.crop_imm((fill_width - width) / 2, (fill_height - height) / 2, width, height)
+}
+
+// Smallest size with the source's aspect ratio that covers a width x height box
+fn fill_dimensions(img_width: u32, img_height: u32, width: u32, height: u32) -> (u32, u32) {
+    let scale = f64::max(width as f64 / img_width as f64, height as f64 / img_height as f64);
+    let fill_width = ((img_width as f64 * scale).round() as u32).max(width);
+    let fill_height = ((img_height as f64 * scale).round() as u32).max(height);
+    (fill_width, fill_height)
+}
+
+// Share of an --equal-output-size output that is padding, or of the filled image cropped away
+fn equal_size_mismatch(img_width: u32, img_height: u32, config: &Config) -> Option<f64> {
+    let (width, height) = config.equal_output_size?;
+    let box_area = width as f64 * height as f64;
+    let mismatch = match config.equal_output_fit {
+        EqualFit::Pad => {
+            let (fit_width, fit_height) = compute_resize(img_width, img_height, width, height, true, None, false);
+            1.0 - fit_width as f64 * fit_height as f64 / box_area
+        }
+        EqualFit::Crop => {
+            let (fill_width, fill_height) = fill_dimensions(img_width, img_height, width, height);
+            1.0 - box_area / (fill_width as f64 * fill_height as f64)
+        }
+    };
+    Some(mismatch)
+}
+
+// Target size for a -d box, using the same fit-inside rounding as DynamicImage::resize;
+// downscale_only caps each side at the source's so nothing is ever enlarged
+fn compute_resize(
+    img_width: u32,
+    img_height: u32,
+    width: u32,
+    height: u32,
+    maintain_ratio: bool,
+    round_to: Option<u32>,
+    downscale_only: bool,
+) -> (u32, u32) {
+    let (width, height) = if maintain_ratio {
+        let mut ratio = (width as f64 / img_width as f64).min(height as f64 / img_height as f64);
+        if downscale_only {
+            ratio = ratio.min(1.0);
+        }
+        (
+            ((img_width as f64 * ratio).round() as u32).max(1),
+            ((img_height as f64 * ratio).round() as u32).max(1),
+        )
+    } else if downscale_only {
+        (width.min(img_width), height.min(img_height))
+    } else {
+        (width, height)
+    };
+
+    match round_to {
+        Some(multiple) => (round_down(width, multiple), round_down(height, multiple)),
+        None => (width, height),
+    }
 }
 
-fn resize_image(img: DynamicImage, width: u32, height: u32, maintain_ratio: bool) -> DynamicImage {
-    if maintain_ratio {
-        img.resize(width, height, image::imageops::FilterType::Lanczos3)
+// Sides already smaller than the multiple are left alone rather than collapsing to zero
+fn round_down(value: u32, multiple: u32) -> u32 {
+    if value >= multiple {
+        value / multiple * multiple
     } else {
-        img.resize_exact(width, height, image::imageops::FilterType::Lanczos3)
+        value
     }
 }
 
-fn get_output_path(
-    input_path: &Path,
-    config: &Config,
-) -> Result<PathBuf, Box<dyn std::error::Error>> {
-    let output_dir = match &config.output_dir {
+fn resize_to_megapixels(img: DynamicImage, megapixels: f64, round_to: Option<u32>, resampling: Resampling) -> DynamicImage {
+    let target_pixels = megapixels * 1_000_000.0;
+    let current_pixels = img.width() as f64 * img.height() as f64;
+    if current_pixels <= target_pixels {
+        return img;
+    }
+
+    // Round down so the result never goes over the requested pixel count
+    let scale = (target_pixels / current_pixels).sqrt();
+    let mut width = ((img.width() as f64 * scale) as u32).max(1);
+    let mut height = ((img.height() as f64 * scale) as u32).max(1);
+    if let Some(multiple) = round_to {
+        width = round_down(width, multiple);
+        height = round_down(height, multiple);
+    }
+    resample(&img, width, height, resampling)
+}
+
+fn resize_to_edge(
+    img: DynamicImage,
+    edge: Edge,
+    allow_upscale: bool,
+    round_to: Option<u32>,
+    resampling: Resampling,
+) -> DynamicImage {
+    let (width, height) = img.dimensions();
+    let (current, target) = match edge {
+        Edge::Long(pixels) => (width.max(height), pixels),
+        Edge::Short(pixels) => (width.min(height), pixels),
+    };
+    if current == target || (current < target && !allow_upscale) {
+        return img;
+    }
+
+    // The chosen side lands exactly on the target; the other follows the aspect ratio
+    let scale = target as f64 / current as f64;
+    let scaled = |side: u32| if side == current { target } else { ((side as f64 * scale).round() as u32).max(1) };
+    let (mut new_width, mut new_height) = (scaled(width), scaled(height));
+    if let Some(multiple) = round_to {
+        new_width = round_down(new_width, multiple);
+        new_height = round_down(new_height, multiple);
+    }
+    resample(&img, new_width, new_height, resampling)
+}
+
+fn output_dir_for(input_path: &Path, config: &Config) -> PathBuf {
+    match &config.output_dir {
         Some(dir) => dir.clone(),
         None => {
             let parent = input_path.parent().unwrap_or(Path::new("."));
             parent.join("resized")
         }
-    };
+    }
+}
+
+// Create every output directory once so parallel workers don't race on create_dir_all
+fn ensure_output_dirs(images: &[PathBuf], config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    if config.overwrite_input {
+        return Ok(());
+    }
+    let dirs: BTreeSet<PathBuf> = images.iter().map(|path| output_dir_for(path, config)).collect();
+    for dir in dirs {
+        fs::create_dir_all(&dir)?;
+    }
+    Ok(())
+}
+
+fn get_output_path(
+    input_path: &Path,
+    result: &CompressionResult,
+    index: usize,
+    config: &Config,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    // The suffix and naming options don't apply when the original is replaced
+    if config.overwrite_input {
+        return Ok(input_path.to_path_buf());
+    }
+    if let Some(output_file) = &config.output_file {
+        return Ok(output_file.clone());
+    }
 
-    fs::create_dir_all(&output_dir)?;
+    let format = result.format;
+    let output_dir = output_dir_for(input_path, config);
+
+    let file_stem = input_path.file_stem().unwrap().to_string_lossy();
+    // Re-derive the name from the original stem so an earlier output keeps its name
+    let file_stem = match file_stem.strip_suffix(RESIZED_SUFFIX) {
+        Some(original) if config.strip_resized_suffix && !original.is_empty() => original,
+        _ => &file_stem,
+    };
+    // Keep the original extension spelling unless the format changed
+    let extension = match get_image_format(input_path) {
+        _ if config.raw_output => match input_path.extension() {
+            Some(ext) if is_raw_file(input_path) => ext.to_os_string(),
+            _ => "raw".into(),
+        },
+        Ok(input_format) if input_format == format => input_path.extension().unwrap_or_default().to_os_string(),
+        _ => format_extension(format).into(),
+    };
+    
+    // A different extension can't collide with the input, so the suffix is optional
+    let converted = !input_path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case(&extension));
+    // An --output that is the input's own folder would otherwise have the output replace its original
+    let elsewhere = config.no_suffix_in_output_dir
+        && config.output_dir.is_some()
+        && !same_file(&output_dir, input_path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new(".")));
+    let suffix = if (converted && config.no_suffix_when_converting) || elsewhere { "" } else { RESIZED_SUFFIX };
 
-    let file_stem = input_path.file_stem().unwrap();
-    let extension = input_path.extension().unwrap_or_default();
+    let file_name = match &config.name_template {
+        Some(template) => template
+            .iter()
+            .map(|part| match part {
+                TemplatePart::Literal(text) => text.clone(),
+                TemplatePart::Stem => file_stem.to_string(),
+                TemplatePart::Width => result.width.to_string(),
+                TemplatePart::Height => result.height.to_string(),
+                TemplatePart::Quality => result.quality.to_string(),
+                TemplatePart::Scale => format!("{:.0}", result.scale * 100.0),
+                TemplatePart::Format if config.raw_output => "raw".to_string(),
+                TemplatePart::Format => format_name(format).to_lowercase(),
+                TemplatePart::Index => index.to_string(),
+                TemplatePart::Ext => extension.to_string_lossy().into_owned(),
+            })
+            .collect(),
+        // Add suffix to avoid overwriting
+        None if config.flatten_gif && input_format(input_path) == Some(ImageFormat::Gif) => format!("{}_frame{}{}.{}",
+            file_stem,
+            config.frame.unwrap_or(0),
+            suffix,
+            extension.to_string_lossy()
+        ),
+        None => format!("{}{}.{}", 
+            file_stem, 
+            suffix,
+            extension.to_string_lossy()
+        ),
+    };
     
-    // Add suffix to avoid overwriting
-    let file_name = format!("{}_resized.{}", 
-        file_stem.to_string_lossy(), 
-        extension.to_string_lossy()
-    );
+    let file_name = match config.date_prefix.then(|| capture_date(input_path, config.date_fallback)).flatten() {
+        Some(date) => format!("{}_{}", date, file_name),
+        None => file_name,
+    };
     
     Ok(output_dir.join(file_name))
 }
 
-fn save_image(
-    img: &DynamicImage,
-    path: &Path,
-    quality: u8,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let format = get_image_format(path)?;
-    let buffer = save_to_buffer(img, format, quality)?;
-    fs::write(path, buffer)?;
-    Ok(())
+// Capture date as YYYYMMDD, preferring when the photo was taken over when it was last edited
+fn capture_date(path: &Path, fallback: DateFallback) -> Option<String> {
+    exif_date(path).or_else(|| match fallback {
+        DateFallback::Mtime => modified_date(path),
+        DateFallback::Skip => None,
+    })
+}
+
+fn exif_date(path: &Path) -> Option<String> {
+    use exif::{In, Tag, Value};
+
+    let mut reader = std::io::BufReader::new(fs::File::open(path).ok()?);
+    let exif = exif::Reader::new().read_from_container(&mut reader).ok()?;
+    [Tag::DateTimeOriginal, Tag::DateTime].into_iter().find_map(|tag| {
+        match &exif.get_field(tag, In::PRIMARY)?.value {
+            Value::Ascii(values) => parse_exif_date(values.first()?),
+            _ => None,
+        }
+    })
+}
+
+fn exif_dimensions(path: &Path) -> Option<(u32, u32)> {
+    use exif::{In, Tag};
+
+    let mut reader = std::io::BufReader::new(fs::File::open(path).ok()?);
+    let exif = exif::Reader::new().read_from_container(&mut reader).ok()?;
+    // Either may be stored as SHORT or LONG
+    let dimension = |tag| exif.get_field(tag, In::PRIMARY)?.value.get_uint(0);
+    Some((dimension(Tag::PixelXDimension)?, dimension(Tag::PixelYDimension)?))
+}
+
+// EXIF dates look like "2024:06:01 12:30:00"; cameras without a clock write zeros or blanks
+fn parse_exif_date(value: &[u8]) -> Option<String> {
+    let date = value.get(..10)?;
+    if date[4] != b':' || date[7] != b':' {
+        return None;
+    }
+    let digits: String = [&date[..4], &date[5..7], &date[8..10]]
+        .concat()
+        .into_iter()
+        .map(char::from)
+        .collect();
+    if !digits.bytes().all(|b| b.is_ascii_digit()) || digits.starts_with("0000") {
+        return None;
+    }
+    Some(digits)
+}
+
+fn modified_date(path: &Path) -> Option<String> {
+    let modified = fs::metadata(path).ok()?.modified().ok()?;
+    let days = modified.duration_since(std::time::UNIX_EPOCH).ok()?.as_secs() / 86400;
+
+    // Days since 1970-01-01 to a UTC civil date (Howard Hinnant's algorithm)
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    Some(format!("{:04}{:02}{:02}", year, month, day))
+}
+
+// PNG, GIF, BMP, TIFF and ICO are lossless and ignore quality, so they report full quality
+// unless --quality-curve gives PNG a quality to honor
+fn default_quality(format: ImageFormat, config: &Config) -> u8 {
+    let qualities = config.format_quality;
+    match format {
+        ImageFormat::Jpeg => qualities.jpeg.unwrap_or(DEFAULT_QUALITY),
+        ImageFormat::WebP => qualities.webp.unwrap_or(DEFAULT_QUALITY),
+        ImageFormat::Png if config.quality_curve.is_some() => qualities.png.unwrap_or(100),
+        _ => 100,
+    }
 }
 
 fn save_to_buffer(
     img: &DynamicImage,
     format: ImageFormat,
     quality: u8,
+    config: &Config,
 ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
     let mut buffer = Cursor::new(Vec::new());
+
+    let flattened;
+    let img = if config.transparent_to_white && img.color().has_alpha() {
+        flattened = DynamicImage::ImageRgb8(flatten_onto(img, image::Rgba([255, 255, 255, 255])));
+        &flattened
+    } else {
+        img
+    };
     
     match format {
         ImageFormat::Jpeg => {
-            let encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut buffer, quality);
-            img.write_with_encoder(encoder)?;
+            buffer = Cursor::new(encode_jpeg(img, quality, config)?);
+        }
+        ImageFormat::Png if matches!(config.png_bit_depth, Some(1 | 2 | 4)) => {
+            buffer = Cursor::new(encode_packed_gray_png(img, config.png_bit_depth.unwrap())?);
+        }
+        ImageFormat::Png if config.quality_curve.is_some() => {
+            let point = curve_point(config.quality_curve.as_deref().unwrap(), quality);
+            if point.colors > 0 {
+                buffer = Cursor::new(encode_indexed_png(img, point)?);
+            } else {
+                let compression = match point.compression {
+                    PngCompression::Fast => image::codecs::png::CompressionType::Fast,
+                    PngCompression::Default => image::codecs::png::CompressionType::Default,
+                    PngCompression::Best => image::codecs::png::CompressionType::Best,
+                };
+                let filter = match point.filter {
+                    PngFilter::None => image::codecs::png::FilterType::NoFilter,
+                    PngFilter::Sub => image::codecs::png::FilterType::Sub,
+                    PngFilter::Up => image::codecs::png::FilterType::Up,
+                    PngFilter::Avg => image::codecs::png::FilterType::Avg,
+                    PngFilter::Paeth => image::codecs::png::FilterType::Paeth,
+                    PngFilter::Adaptive => image::codecs::png::FilterType::Adaptive,
+                };
+                img.write_with_encoder(image::codecs::png::PngEncoder::new_with_quality(&mut buffer, compression, filter))?;
+            }
         }
         ImageFormat::Png => {
-            // PNG uses compression level (0-9), map quality to compression
+            // Lossless, so quality has nothing to trade; always use the smallest compression level
             let compression = image::codecs::png::CompressionType::Best;
             let encoder = image::codecs::png::PngEncoder::new_with_quality(
                 &mut buffer,
                 compression,
                 image::codecs::png::FilterType::Adaptive,
             );
-            img.write_with_encoder(encoder)?;
+            match config.png_bit_depth {
+                Some(8) => convert_depth(img, false).write_with_encoder(encoder)?,
+                Some(16) => convert_depth(img, true).write_with_encoder(encoder)?,
+                _ => img.write_with_encoder(encoder)?,
+            }
         }
         ImageFormat::WebP => {
-            // For WebP, fall back to JPEG for now
-            let encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut buffer, quality);
-            img.write_with_encoder(encoder)?;
+            buffer = Cursor::new(encode_webp(img, quality, config)?);
+        }
+        #[cfg(feature = "qoi")]
+        ImageFormat::Qoi => {
+            buffer = Cursor::new(encode_qoi(img)?);
         }
         _ => {
             img.write_to(&mut buffer, format)?;
         }
     }
     
-    Ok(buffer.into_inner())
+    let mut data = buffer.into_inner();
+
+    // PNG encoder has no density setting, so add the pHYs chunk afterwards
+    if format == ImageFormat::Png {
+        if let Some(dpi) = config.dpi {
+            let pixels_per_meter = (dpi as f64 / 0.0254).round() as u32;
+            let mut phys = Vec::with_capacity(9);
+            phys.extend_from_slice(&pixels_per_meter.to_be_bytes());
+            phys.extend_from_slice(&pixels_per_meter.to_be_bytes());
+            phys.push(1); // unit: meter
+            insert_png_chunk(&mut data, b"pHYs", &phys);
+        }
+    }
+
+    // Comments are added after encoding so they fit inside the size target
+    if let Some(comment) = &config.comment {
+        match format {
+            ImageFormat::Jpeg => insert_jpeg_comment(&mut data, comment),
+            ImageFormat::Png => {
+                // tEXt is Latin-1 only, anything else needs an uncompressed iTXt chunk
+                let latin1: Option<Vec<u8>> = comment.chars().map(|c| u8::try_from(c).ok()).collect();
+                match latin1 {
+                    Some(text) => insert_png_chunk(&mut data, b"tEXt", &[b"Comment\0".as_slice(), &text].concat()),
+                    None => insert_png_chunk(&mut data, b"iTXt", &[b"Comment\0\0\0\0\0".as_slice(), comment.as_bytes()].concat()),
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(data)
+}
+
+fn encode_jpeg(
+    img: &DynamicImage,
+    quality: u8,
+    config: &Config,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    use jpeg_encoder::{ColorType, Encoder, PixelDensity, SamplingFactor};
+
+    let width = u16::try_from(img.width()).map_err(|_| "JPEG width is limited to 65535 pixels")?;
+    let height = u16::try_from(img.height()).map_err(|_| "JPEG height is limited to 65535 pixels")?;
+
+    let mut data = Vec::new();
+    let mut encoder = Encoder::new(&mut data, quality);
+    encoder.set_sampling_factor(match config.subsampling.unwrap_or(Subsampling::S444) {
+        Subsampling::S444 => SamplingFactor::R_4_4_4,
+        Subsampling::S422 => SamplingFactor::R_4_2_2,
+        Subsampling::S420 => SamplingFactor::R_4_2_0,
+    });
+    if let Some(dpi) = config.dpi {
+        encoder.set_density(PixelDensity::dpi(dpi));
+    }
+    // Applies to every encode of the size search, so the quality chosen reflects the optimized size
+    encoder.set_optimized_huffman_tables(config.optimize);
+
+    // JPEG has no alpha channel, so it is dropped like the previous encoder did
+    match img {
+        DynamicImage::ImageLuma8(gray) => encoder.encode(gray.as_raw(), width, height, ColorType::Luma)?,
+        DynamicImage::ImageRgb8(rgb) => encoder.encode(rgb.as_raw(), width, height, ColorType::Rgb)?,
+        _ if !img.color().has_color() => encoder.encode(img.to_luma8().as_raw(), width, height, ColorType::Luma)?,
+        _ => encoder.encode(img.to_rgb8().as_raw(), width, height, ColorType::Rgb)?,
+    }
+
+    Ok(data)
+}
+
+fn encode_webp(
+    img: &DynamicImage,
+    quality: u8,
+    config: &Config,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut webp_config = webp::WebPConfig::new().map_err(|_| "Failed to initialize WebP encoder")?;
+    webp_config.quality = quality as f32;
+    webp_config.method = config.webp_effort as i32;
+    webp_config.thread_level = webp_thread_level(config);
+    if config.webp_lossless {
+        // Quality becomes compression effort, and hidden RGB under transparent pixels is kept
+        webp_config.lossless = 1;
+        webp_config.exact = 1;
+    }
+    set_near_lossless(&mut webp_config, config);
+
+    let (width, height) = (img.width(), img.height());
+    let encoded = if img.color().has_alpha() {
+        let rgba = img.to_rgba8();
+        webp::Encoder::from_rgba(rgba.as_raw(), width, height).encode_advanced(&webp_config)
+    } else {
+        let rgb = img.to_rgb8();
+        webp::Encoder::from_rgb(rgb.as_raw(), width, height).encode_advanced(&webp_config)
+    };
+
+    Ok(encoded.map_err(|e| format!("WebP encoding failed: {:?}", e))?.to_vec())
+}
+
+// Near-lossless is a preprocessing step of the lossless encoder, so quality becomes effort here too
+fn set_near_lossless(webp_config: &mut webp::WebPConfig, config: &Config) {
+    if let Some(level) = config.near_lossless {
+        webp_config.lossless = 1;
+        webp_config.near_lossless = i32::from(level);
+    }
+}
+
+// libwebp can only switch its second worker thread on or off, and JPEG and PNG encode on one thread
+fn webp_thread_level(config: &Config) -> i32 {
+    i32::from(config.encode_threads > 1)
+}
+
+fn convert_depth(img: &DynamicImage, sixteen_bit: bool) -> DynamicImage {
+    let color = img.color();
+    match (color.has_color(), color.has_alpha(), sixteen_bit) {
+        (false, false, false) => DynamicImage::ImageLuma8(img.to_luma8()),
+        (false, true, false) => DynamicImage::ImageLumaA8(img.to_luma_alpha8()),
+        (true, false, false) => DynamicImage::ImageRgb8(img.to_rgb8()),
+        (true, true, false) => DynamicImage::ImageRgba8(img.to_rgba8()),
+        (false, false, true) => DynamicImage::ImageLuma16(img.to_luma16()),
+        (false, true, true) => DynamicImage::ImageLumaA16(img.to_luma_alpha16()),
+        (true, false, true) => DynamicImage::ImageRgb16(img.to_rgb16()),
+        (true, true, true) => DynamicImage::ImageRgba16(img.to_rgba16()),
+    }
+}
+
+fn encode_packed_gray_png(img: &DynamicImage, depth: u8) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    // Sub-byte depths only exist for grayscale, anything else would silently lose color
+    let color = img.color();
+    if color.has_color() || color.has_alpha() {
+        return Err(format!(
+            "PNG bit depth {} requires a grayscale image without alpha, got {:?}",
+            depth, color
+        )
+        .into());
+    }
+
+    let gray = img.to_luma8();
+    let max_level = (1u32 << depth) - 1;
+    let pixels_per_byte = 8 / depth as usize;
+    let row_bytes = (gray.width() as usize).div_ceil(pixels_per_byte);
+
+    let mut packed = vec![0u8; row_bytes * gray.height() as usize];
+    for (y, row) in gray.rows().enumerate() {
+        for (x, pixel) in row.enumerate() {
+            let level = ((pixel[0] as u32 * max_level + 127) / 255) as u8;
+            let shift = 8 - depth as usize * (x % pixels_per_byte + 1);
+            packed[y * row_bytes + x / pixels_per_byte] |= level << shift;
+        }
+    }
+
+    let mut data = Vec::new();
+    let mut encoder = png::Encoder::new(&mut data, gray.width(), gray.height());
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(match depth {
+        1 => png::BitDepth::One,
+        2 => png::BitDepth::Two,
+        _ => png::BitDepth::Four,
+    });
+    encoder.set_compression(png::Compression::Best);
+    encoder.set_adaptive_filter(png::AdaptiveFilterType::Adaptive);
+    encoder.write_header()?.write_image_data(&packed)?;
+
+    Ok(data)
+}
+
+// Palette of at most point.colors entries; images that already have that few colors keep them exactly
+fn encode_indexed_png(img: &DynamicImage, point: CurvePoint) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let rgba = img.to_rgba8();
+    let max_colors = point.colors as usize;
+
+    let mut palette: Vec<[u8; 4]> = Vec::new();
+    let mut lookup: BTreeMap<[u8; 4], u8> = BTreeMap::new();
+    let mut indices = Vec::with_capacity(rgba.pixels().len());
+    for pixel in rgba.pixels() {
+        let index = match lookup.get(&pixel.0) {
+            Some(&index) => index,
+            None if palette.len() < max_colors => {
+                let index = palette.len() as u8;
+                palette.push(pixel.0);
+                lookup.insert(pixel.0, index);
+                index
+            }
+            None => break,
+        };
+        indices.push(index);
+    }
+
+    if indices.len() < rgba.pixels().len() {
+        let quantizer = color_quant::NeuQuant::new(10, max_colors, rgba.as_raw());
+        palette = quantizer.color_map_rgba().chunks_exact(4).map(|c| [c[0], c[1], c[2], c[3]]).collect();
+        indices = rgba.pixels().map(|pixel| quantizer.index_of(&pixel.0) as u8).collect();
+    }
+
+    let depth: u8 = match palette.len() {
+        0..=2 => 1,
+        3..=4 => 2,
+        5..=16 => 4,
+        _ => 8,
+    };
+    let pixels_per_byte = 8 / depth as usize;
+    let row_bytes = (rgba.width() as usize).div_ceil(pixels_per_byte);
+    let mut packed = vec![0u8; row_bytes * rgba.height() as usize];
+    for (i, &index) in indices.iter().enumerate() {
+        let (y, x) = (i / rgba.width() as usize, i % rgba.width() as usize);
+        let shift = 8 - depth as usize * (x % pixels_per_byte + 1);
+        packed[y * row_bytes + x / pixels_per_byte] |= index << shift;
+    }
+
+    let mut data = Vec::new();
+    let mut encoder = png::Encoder::new(&mut data, rgba.width(), rgba.height());
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(match depth {
+        1 => png::BitDepth::One,
+        2 => png::BitDepth::Two,
+        4 => png::BitDepth::Four,
+        _ => png::BitDepth::Eight,
+    });
+    encoder.set_palette(palette.iter().flat_map(|c| [c[0], c[1], c[2]]).collect::<Vec<u8>>());
+    // tRNS may stop after the last translucent entry; the rest are opaque
+    if let Some(last) = palette.iter().rposition(|c| c[3] < 255) {
+        encoder.set_trns(palette[..=last].iter().map(|c| c[3]).collect::<Vec<u8>>());
+    }
+    encoder.set_compression(match point.compression {
+        PngCompression::Fast => png::Compression::Fast,
+        PngCompression::Default => png::Compression::Default,
+        PngCompression::Best => png::Compression::Best,
+    });
+    match point.filter {
+        PngFilter::Adaptive => encoder.set_adaptive_filter(png::AdaptiveFilterType::Adaptive),
+        filter => encoder.set_filter(match filter {
+            PngFilter::Sub => png::FilterType::Sub,
+            PngFilter::Up => png::FilterType::Up,
+            PngFilter::Avg => png::FilterType::Avg,
+            PngFilter::Paeth => png::FilterType::Paeth,
+            _ => png::FilterType::NoFilter,
+        }),
+    }
+    encoder.write_header()?.write_image_data(&packed)?;
+
+    Ok(data)
+}
+
+fn insert_png_chunk(data: &mut Vec<u8>, chunk_type: &[u8; 4], payload: &[u8]) {
+    // 8 byte signature + IHDR chunk (4 length + 4 type + 13 data + 4 crc)
+    const IHDR_END: usize = 33;
+    if data.len() < IHDR_END {
+        return;
+    }
+
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(chunk_type);
+    hasher.update(payload);
+
+    let mut chunk = Vec::with_capacity(payload.len() + 12);
+    chunk.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    chunk.extend_from_slice(chunk_type);
+    chunk.extend_from_slice(payload);
+    chunk.extend_from_slice(&hasher.finalize().to_be_bytes());
+
+    data.splice(IHDR_END..IHDR_END, chunk);
+}
+
+fn insert_jpeg_comment(data: &mut Vec<u8>, comment: &str) {
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return;
+    }
+
+    // Place the COM segment after SOI and the JFIF APP0 segment, if there is one
+    let mut offset = 2;
+    if data.len() >= 6 && data[2..4] == [0xFF, 0xE0] {
+        offset += 2 + u16::from_be_bytes([data[4], data[5]]) as usize;
+    }
+
+    let mut segment = Vec::with_capacity(comment.len() + 4);
+    segment.extend_from_slice(&[0xFF, 0xFE]);
+    segment.extend_from_slice(&((comment.len() + 2) as u16).to_be_bytes());
+    segment.extend_from_slice(comment.as_bytes());
+
+    data.splice(offset..offset, segment);
+}
+
+// QOI stores 8-bit RGB or RGBA only, so other layouts are converted first
+#[cfg(feature = "qoi")]
+fn encode_qoi(img: &DynamicImage) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let data = if img.color().has_alpha() {
+        qoi::encode_to_vec(img.to_rgba8().as_raw(), img.width(), img.height())?
+    } else {
+        qoi::encode_to_vec(img.to_rgb8().as_raw(), img.width(), img.height())?
+    };
+    Ok(data)
+}
+
+fn format_name(format: ImageFormat) -> &'static str {
+    match format {
+        ImageFormat::Jpeg => "JPEG",
+        ImageFormat::Png => "PNG",
+        ImageFormat::Gif => "GIF",
+        ImageFormat::Bmp => "BMP",
+        ImageFormat::WebP => "WebP",
+        ImageFormat::Tiff => "TIFF",
+        ImageFormat::Ico => "ICO",
+        ImageFormat::Qoi => "QOI",
+        _ => "Other",
+    }
+}
+
+fn format_extension(format: ImageFormat) -> &'static str {
+    match format {
+        ImageFormat::Jpeg => "jpg",
+        ImageFormat::Png => "png",
+        ImageFormat::Gif => "gif",
+        ImageFormat::Bmp => "bmp",
+        ImageFormat::WebP => "webp",
+        ImageFormat::Tiff => "tiff",
+        ImageFormat::Ico => "ico",
+        ImageFormat::Qoi => "qoi",
+        _ => "img",
+    }
+}
+
+// Lossless WebP sources stay lossless unless --webp-lossy says otherwise
+fn keeps_lossless_webp(path: &Path, config: &Config) -> bool {
+    !config.webp_lossy && is_lossless_webp(path)
+}
+
+fn is_lossless_webp(path: &Path) -> bool {
+    fs::read(path)
+        .ok()
+        .and_then(|data| webp::BitstreamFeatures::new(&data)?.format())
+        .is_some_and(|format| matches!(format, webp::BitstreamFormat::Lossless))
+}
+
+// Sniff the format from the file header, independent of the extension
+fn detect_format(path: &Path) -> Option<ImageFormat> {
+    let mut header = [0u8; 32];
+    let len = fs::File::open(path).ok()?.read(&mut header).ok()?;
+    image::guess_format(&header[..len]).ok()
+}
+
+fn input_format(path: &Path) -> Option<ImageFormat> {
+    detect_format(path).or_else(|| get_image_format(path).ok())
 }
 
 fn get_image_format(path: &Path) -> Result<ImageFormat, Box<dyn std::error::Error>> {
@@ -511,6 +5953,271 @@ fn get_image_format(path: &Path) -> Result<ImageFormat, Box<dyn std::error::Erro
         Some("bmp") => Ok(ImageFormat::Bmp),
         Some("webp") => Ok(ImageFormat::WebP),
         Some("tiff") | Some("tif") => Ok(ImageFormat::Tiff),
+        Some("ico") => Ok(ImageFormat::Ico),
+        #[cfg(feature = "qoi")]
+        Some("qoi") => Ok(ImageFormat::Qoi),
         _ => Err("Unsupported image format".into()),
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+
+    // A fresh folder per test under the system temp dir
+    fn temp_dir(name: &str) -> PathBuf {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "image_resizer_{}_{}_{}",
+            name,
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // A gradient with some noise so sizes respond to quality and scale like a photo would
+    fn photo(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+            let noise = (x.wrapping_mul(7919) ^ y.wrapping_mul(104729)) % 32;
+            Rgb([(x * 255 / width) as u8 ^ noise as u8, (y * 255 / height) as u8, ((x + y) % 256) as u8])
+        }))
+    }
+
+    fn write_image(path: &Path, width: u32, height: u32) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        photo(width, height).save(path).unwrap();
+    }
+
+    fn config(args: &[&str]) -> Config {
+        let matches = cli().try_get_matches_from(std::iter::once("image-resizer").chain(args.iter().copied())).unwrap();
+        config_from_matches(&matches).unwrap()
+    }
+
+    fn files_under(dir: &Path) -> Vec<PathBuf> {
+        WalkDir::new(dir)
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_file())
+            .map(|entry| entry.into_path())
+            .collect()
+    }
+
+    #[test]
+    fn repeated_runs_do_not_stack_resized_suffix() {
+        let dir = temp_dir("rerun");
+        write_image(&dir.join("a.jpg"), 64, 48);
+        write_image(&dir.join("sub").join("b.jpg"), 64, 48);
+        write_image(&dir.join("resized").join("old_resized.jpg"), 64, 48);
+        let input = dir.to_str().unwrap();
+        let output = dir.join("out");
+
+        for _ in 0..2 {
+            process_images(&config(&["-i", input, "-d", "32x24", "-q"])).unwrap();
+        }
+        for _ in 0..2 {
+            process_images(&config(&["-i", input, "-d", "32x24", "-q", "-o", output.to_str().unwrap()])).unwrap();
+        }
+
+        let files = files_under(&dir);
+        assert!(files.iter().any(|path| path.starts_with(&output)));
+        assert!(
+            !files.iter().any(|path| path.to_string_lossy().contains("_resized_resized")),
+            "{:?}",
+            files
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // A JPEG target only reachable at a low quality at full size, so each --prefer mode has
+    // to trade differently
+    fn prefer_case(prefer: &str) -> (CompressionResult, Config) {
+        let img = photo(480, 360);
+        let config = config(&["-i", ".", "-s", "1", "--prefer", prefer, "-c"]);
+        let low = config.min_quality + (config.max_quality - config.min_quality) / 4;
+        let target = save_to_buffer(&img, ImageFormat::Jpeg, low, &config).unwrap().len() as u64;
+        let result = smart_compress(&img, target, ImageFormat::Jpeg, &config).unwrap().unwrap();
+        assert!(result.data.len() as u64 <= target);
+        (result, config)
+    }
+
+    #[test]
+    fn prefer_quality_keeps_full_size() {
+        let (result, config) = prefer_case("quality");
+        assert_eq!(result.scale, 1.0);
+        assert!(result.quality < config.max_quality);
+    }
+
+    #[test]
+    fn prefer_scale_keeps_max_quality() {
+        let (result, config) = prefer_case("scale");
+        assert!(result.scale < 1.0);
+        assert_eq!(result.quality, config.max_quality);
+    }
+
+    #[test]
+    fn prefer_balanced_shrinks_until_quality_is_mid_range() {
+        let (result, config) = prefer_case("balanced");
+        let (quality, _) = prefer_case("quality");
+        assert!(result.scale < 1.0);
+        assert!(result.quality >= config.min_quality + (config.max_quality - config.min_quality) / 2);
+        assert!(result.quality > quality.quality);
+    }
+
+    #[test]
+    fn lossless_webp_round_trips_pixels() {
+        let dir = temp_dir("lossless_webp");
+        let input = dir.join("a.webp");
+        // Transparent pixels too, which the encoder would otherwise be free to change
+        let mut rgba = photo(64, 48).to_rgba8();
+        for (x, _, pixel) in rgba.enumerate_pixels_mut() {
+            if x < 8 {
+                pixel[3] = 0;
+            }
+        }
+        fs::write(&input, &*webp::Encoder::from_rgba(rgba.as_raw(), 64, 48).encode_lossless()).unwrap();
+
+        process_images(&config(&["-i", input.to_str().unwrap(), "-s", "1000", "-q"])).unwrap();
+
+        let output = dir.join("resized").join("a_resized.webp");
+        assert!(is_lossless_webp(&output));
+        assert_eq!(image::open(&output).unwrap().to_rgba8(), image::open(&input).unwrap().to_rgba8());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sidecar_reaches_the_result_message() {
+        let dir = temp_dir("sidecar");
+        let input = dir.join("a.jpg");
+        write_image(&input, 64, 48);
+        fs::write(sidecar_path(&input), "dimensions = \"40x40\"\nmaintain-ratio = true\n").unwrap();
+        // Created by process_images for a whole batch
+        fs::create_dir_all(dir.join("resized")).unwrap();
+
+        let result = process_single_image_with_result(&input, 0, &config(&["-i", input.to_str().unwrap(), "-d", "32x24", "-q"]));
+
+        assert!(result.success, "{}", result.message);
+        assert_eq!(result.dimensions, Some((40, 30)));
+        assert_eq!(result.message, "(aspect ratio kept, requested 40x40)");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn copy_unsupported_mirrors_the_input_tree() {
+        let dir = temp_dir("copy_unsupported");
+        let input = dir.join("in");
+        let output = dir.join("out");
+        fs::create_dir_all(input.join("sub")).unwrap();
+        fs::write(input.join("notes.txt"), "top").unwrap();
+        fs::write(input.join("sub").join("notes.txt"), "nested").unwrap();
+
+        let config = config(&["-i", input.to_str().unwrap(), "-o", output.to_str().unwrap(), "--copy-unsupported"]);
+        let files = collect_files(&input, &config).unwrap();
+        assert!(copy_unsupported(&files, &config).iter().all(Result::is_ok));
+
+        assert_eq!(fs::read_to_string(output.join("notes.txt")).unwrap(), "top");
+        assert_eq!(fs::read_to_string(output.join("sub").join("notes.txt")).unwrap(), "nested");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unreachable_animation_target_fails() {
+        let frames = (0..3)
+            .map(|i| {
+                let img = photo(320 + i, 240).crop_imm(0, 0, 320, 240).to_rgba8();
+                image::Frame::from_parts(img, 0, 0, image::Delay::from_numer_denom_ms(100, 1))
+            })
+            .collect();
+        let config = config(&["-i", ".", "-s", "1", "--animated-webp"]);
+
+        let error = render_animation(frames, &config, &mut StageTimings::default()).err().unwrap();
+        assert!(error.to_string().starts_with("Could not achieve target file size"));
+    }
+
+    #[test]
+    fn parallel_totals_match_sequential() {
+        let dir = temp_dir("parallel");
+        let input = dir.join("in");
+        for i in 0..12 {
+            write_image(&input.join(format!("{}.jpg", i)), 48 + i * 8, 40 + i * 4);
+        }
+        fs::write(input.join("broken.jpg"), b"not an image").unwrap();
+
+        let summary = |name: &str, extra: &[&str]| {
+            let report = dir.join(format!("{}.json", name));
+            let output = dir.join(name);
+            let mut args = vec!["-i", input.to_str().unwrap(), "-d", "40x30", "-s", "2", "-c", "-q"];
+            args.extend(["-o", output.to_str().unwrap(), "--report", report.to_str().unwrap()]);
+            args.extend(extra);
+            process_images(&config(&args)).unwrap();
+            let report: serde_json::Value = serde_json::from_str(&fs::read_to_string(&report).unwrap()).unwrap();
+            report["summary"].clone()
+        };
+
+        let sequential = summary("sequential", &[]);
+        let parallel = summary("parallel", &["--parallel"]);
+        assert_eq!(sequential["total"], 13);
+        assert_eq!(sequential["failed"], 1);
+        assert_eq!(sequential, parallel);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn seeded_search_matches_full_search() {
+        let full = config(&["-i", ".", "-s", "1"]);
+        let seeded = config(&["-i", ".", "-s", "1", "--sample-pixels"]);
+        // Proxy encodes are left out, since they are what the seeded search trades full-size ones for
+        let search = |img: &DynamicImage, target: u64, config: &Config| {
+            FULL_ENCODES.with(|count| count.set(0));
+            let result = search_quality(img, target, ImageFormat::Jpeg, 1.0, config).unwrap().unwrap();
+            (result, FULL_ENCODES.with(|count| count.get()))
+        };
+        for (width, height) in [(320, 256), (512, 384), (640, 300)] {
+            let img = photo(width, height);
+            for quality in [20, 50, 80] {
+                let target = save_to_buffer(&img, ImageFormat::Jpeg, quality, &full).unwrap().len() as u64 + 200;
+                let case = format!("{}x{} at {} bytes", width, height, target);
+                assert!(sample_model(&img, ImageFormat::Jpeg, &seeded).unwrap().is_some(), "{}", case);
+                let (expected, full_encodes) = search(&img, target, &full);
+                let (result, seeded_encodes) = search(&img, target, &seeded);
+                assert_eq!(result.quality, expected.quality, "{}", case);
+                assert!(result.data == expected.data, "{}", case);
+                assert!(seeded_encodes < full_encodes, "{}: {} seeded vs {} full encodes", case, seeded_encodes, full_encodes);
+            }
+        }
+    }
+
+    #[test]
+    fn copy_unsupported_passes_undecodable_images_through() {
+        let dir = temp_dir("undecodable");
+        let input = dir.join("in");
+        let output = dir.join("out");
+        write_image(&input.join("good.jpg"), 64, 48);
+        fs::write(input.join("broken.jpg"), b"not an image").unwrap();
+
+        process_images(&config(&["-i", input.to_str().unwrap(), "-o", output.to_str().unwrap(), "--copy-unsupported", "-q"]))
+            .unwrap();
+
+        assert!(output.join("good_resized.jpg").exists());
+        assert_eq!(fs::read(output.join("broken.jpg")).unwrap(), b"not an image");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn replace_file_keeps_the_original_mode() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = temp_dir("replace_mode");
+        let path = dir.join("private.jpg");
+        fs::write(&path, b"original").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+
+        replace_file(&path, b"replaced", None, None).unwrap();
+
+        assert_eq!(fs::read(&path).unwrap(), b"replaced");
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}